@@ -3,7 +3,9 @@ use std::rc::Rc;
 
 use ahash::{HashMap, HashMapExt};
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ipfixrw::borrowed::MessageRef;
 use ipfixrw::parse_ipfix_message;
+use ipfixrw::template_store::TemplateStorage;
 use pprof::criterion::PProfProfiler;
 
 use ipfixrw::information_elements::get_default_formatter;
@@ -35,6 +37,40 @@ fn parse_data_with_template(c: &mut Criterion) {
     });
 }
 
+fn parse_data_borrowed(c: &mut Criterion) {
+    // contains templates 500, 999, 501
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+
+    // contains data sets for templates 999, 500, 999
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+
+    // parse the template so parsing data can be done
+    let _ = parse_ipfix_message(
+        black_box(template_bytes),
+        templates.clone(),
+        formatter.clone(),
+    )
+    .unwrap();
+
+    c.bench_function("data_borrowed", |b| {
+        b.iter(|| {
+            let msg = MessageRef::new(black_box(data_bytes)).unwrap();
+            for set in msg.sets() {
+                let set = set.unwrap();
+                let template = templates.get_template(set.set_id).unwrap();
+                for record in set.data_records(&template) {
+                    for (_, value) in record.unwrap().fields() {
+                        black_box(value.unwrap());
+                    }
+                }
+            }
+        })
+    });
+}
+
 fn parse_template(c: &mut Criterion) {
     // contains templates 500, 999, 501
     let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
@@ -67,6 +103,6 @@ fn profiler() -> PProfProfiler<'static, 'static> {
 criterion_group! {
     name = benches;
     config = Criterion::default().with_profiler(profiler());
-    targets = parse_template, parse_data_with_template
+    targets = parse_template, parse_data_with_template, parse_data_borrowed
 }
 criterion_main!(benches);
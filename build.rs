@@ -59,10 +59,9 @@ fn main() {
             "dateTimeNanoseconds" => "DateTimeNanoseconds",
             "ipv4Address" => "Ipv4Addr",
             "ipv6Address" => "Ipv6Addr",
-            // TODO: support for lists [RFC6313]
-            "basicList" => continue,
-            "subTemplateList" => continue,
-            "subTemplateMultiList" => continue,
+            "basicList" => "BasicList",
+            "subTemplateList" => "SubTemplateList",
+            "subTemplateMultiList" => "SubTemplateMultiList",
             "" => continue,
             d => panic!("Unknown abstract data type {d}!"),
         };
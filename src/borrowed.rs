@@ -0,0 +1,513 @@
+//! Zero-copy views over an IPFIX message in a byte buffer.
+//!
+//! Unlike `Message`, nothing is allocated while walking sets, records and
+//! fields: variable length fields are returned as slices of the buffer.
+//! Templates are not inserted into a template store while walking, so
+//! template sets should be parsed with `SetRef::to_set` (or the whole
+//! message with `MessageRef::to_message`).
+
+use std::{io::Cursor, ops::Range, rc::Rc};
+
+use binrw::{BinRead, Endian};
+
+use crate::{
+    error::{Error, IpfixError},
+    information_elements::Formatter,
+    parser::{
+        DataRecord, DataRecordKey, DataRecordType, DataRecordValue, FieldContext, Message,
+        MessageHeader, ParseOptions, Set, StringPolicy,
+    },
+    template_store::{ExpandedFieldSpecifier, Template, TemplateStore},
+};
+
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-3.1>
+#[derive(Clone, Copy, Debug)]
+pub struct MessageRef<'a> {
+    pub header: MessageHeader,
+    buf: &'a [u8],
+}
+
+impl<'a> MessageRef<'a> {
+    /// View the message in `buf`, which must hold exactly one message
+    pub fn new(buf: &'a [u8]) -> Result<Self, Error> {
+        let header = MessageHeader::from_bytes(buf)?;
+        let length = usize::from(header.length);
+        if buf.len() > length {
+            return Err(Error::new(
+                IpfixError::TrailingBytes(buf.len() - length),
+                length as u64,
+            ));
+        }
+        Ok(Self { header, buf })
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    pub fn sets(&self) -> SetRefIter<'a> {
+        SetRefIter {
+            buf: self.buf,
+            offset: MessageHeader::SIZE,
+        }
+    }
+
+    /// Parse into an owned `Message`, inserting any templates into `templates`
+    pub fn to_message(
+        &self,
+        templates: TemplateStore,
+        formatter: Rc<Formatter>,
+    ) -> Result<Message, Error> {
+        crate::parse_ipfix_message(&self.buf, templates, formatter)
+    }
+}
+
+pub struct SetRefIter<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for SetRefIter<'a> {
+    type Item = Result<SetRef<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        if offset >= self.buf.len() {
+            return None;
+        }
+        let Some(set_header) = self.buf.get(offset..offset + 4) else {
+            // too short for even a set header
+            self.offset = self.buf.len();
+            return Some(Err(Error::new(IpfixError::BadSetLength(0), offset as u64)));
+        };
+        let set_id = u16::from_be_bytes([set_header[0], set_header[1]]);
+        let length = u16::from_be_bytes([set_header[2], set_header[3]]);
+
+        // without a usable length, there is no way to find the next set
+        let Some(content) = self
+            .buf
+            .get(offset + 4..offset + usize::from(length))
+            .filter(|_| length > 4)
+        else {
+            self.offset = self.buf.len();
+            return Some(Err(
+                Error::new(IpfixError::BadSetLength(length), 0).in_set(set_id, offset as u64)
+            ));
+        };
+
+        self.offset += usize::from(length);
+        Some(Ok(SetRef {
+            set_id,
+            offset,
+            content,
+        }))
+    }
+}
+
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-3.3>
+#[derive(Clone, Copy, Debug)]
+pub struct SetRef<'a> {
+    pub set_id: u16,
+    /// offset of the set from the start of the message
+    pub offset: usize,
+    content: &'a [u8],
+}
+
+impl<'a> SetRef<'a> {
+    /// The set content, after the set header
+    pub fn content(&self) -> &'a [u8] {
+        self.content
+    }
+
+    pub fn is_data_set(&self) -> bool {
+        self.set_id > 255
+    }
+
+    /// Records in a data set, described by `template`. A record running
+    /// past the end of the set fails with `IpfixError::TruncatedRecord`
+    pub fn data_records<'t>(&self, template: &'t Template) -> DataRecordRefIter<'a, 't> {
+        self.data_records_with_options(template, ParseOptions::default())
+    }
+
+    /// `data_records`, decoding fields with the options of `options`
+    pub fn data_records_with_options<'t>(
+        &self,
+        template: &'t Template,
+        options: ParseOptions,
+    ) -> DataRecordRefIter<'a, 't> {
+        DataRecordRefIter {
+            set_id: self.set_id,
+            content_offset: self.offset + 4,
+            template,
+            options,
+            content: self.content,
+            offset: 0,
+        }
+    }
+
+    /// Parse into an owned `Set`, inserting any templates into `templates`
+    pub fn to_set(&self, templates: TemplateStore, formatter: Rc<Formatter>) -> Result<Set, Error> {
+        self.to_set_with_options(templates, formatter, ParseOptions::default())
+    }
+
+    pub fn to_set_with_options(
+        &self,
+        templates: TemplateStore,
+        formatter: Rc<Formatter>,
+        options: ParseOptions,
+    ) -> Result<Set, Error> {
+        let mut buf = Vec::with_capacity(self.content.len() + 4);
+        buf.extend_from_slice(&self.set_id.to_be_bytes());
+        buf.extend_from_slice(&(self.content.len() as u16 + 4).to_be_bytes());
+        buf.extend_from_slice(self.content);
+        Set::read_args(&mut Cursor::new(buf), (templates, formatter, options))
+            .map_err(|e| Error::from(e).in_set(self.set_id, self.offset as u64))
+    }
+}
+
+pub struct DataRecordRefIter<'a, 't> {
+    set_id: u16,
+    /// offset of the set content in the message
+    content_offset: usize,
+    template: &'t Template,
+    options: ParseOptions,
+    content: &'a [u8],
+    offset: usize,
+}
+
+impl<'a, 't> Iterator for DataRecordRefIter<'a, 't> {
+    type Item = Result<DataRecordRef<'a, 't>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // a template without fields would never make progress
+        if self.template.field_specifiers().is_empty() {
+            return None;
+        }
+        // fewer bytes than the shortest record are padding
+        if self.content.len() - self.offset < self.template.min_record_length() {
+            self.offset = self.content.len();
+            return None;
+        }
+
+        let offset = self.content_offset + self.offset;
+        let mut end = self.offset;
+        for field_spec in self.template.field_specifiers() {
+            let Some((_, field_end)) = field_bounds(self.content, end, field_spec.field_length)
+            else {
+                self.offset = self.content.len();
+                return Some(Err(
+                    Error::new(IpfixError::TruncatedRecord, 0).in_set(self.set_id, offset as u64)
+                ));
+            };
+            end = field_end;
+        }
+        // a record of no bytes would never make progress
+        if end == self.offset {
+            self.offset = self.content.len();
+            return None;
+        }
+
+        let record = DataRecordRef {
+            template_id: self.set_id,
+            offset,
+            template: self.template,
+            options: self.options,
+            data: &self.content[self.offset..end],
+        };
+        self.offset = end;
+        Some(Ok(record))
+    }
+}
+
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-3.4.3>
+#[derive(Clone, Copy, Debug)]
+pub struct DataRecordRef<'a, 't> {
+    template_id: u16,
+    /// offset of the record in the message
+    offset: usize,
+    template: &'t Template,
+    options: ParseOptions,
+    data: &'a [u8],
+}
+
+impl<'a, 't> DataRecordRef<'a, 't> {
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn template_id(&self) -> u16 {
+        self.template_id
+    }
+
+    pub fn template(&self) -> &'t Template {
+        self.template
+    }
+
+    /// Fields in template order
+    pub fn fields(&self) -> FieldRefIter<'a, 't> {
+        FieldRefIter {
+            record: *self,
+            field_specifiers: self.template.field_specifiers().iter().enumerate(),
+            offset: 0,
+        }
+    }
+
+    /// Get the first value for `key`
+    pub fn get(&self, key: &DataRecordKey) -> Option<Result<DataRecordValueRef<'a>, Error>> {
+        self.get_all(key).next()
+    }
+
+    /// Get all values for `key`, in template order
+    pub fn get_all<'k>(
+        &self,
+        key: &'k DataRecordKey,
+    ) -> impl Iterator<Item = Result<DataRecordValueRef<'a>, Error>> + 'k
+    where
+        'a: 'k,
+        't: 'k,
+    {
+        self.fields()
+            .filter(move |(field_spec, _)| field_spec.name == *key)
+            .map(|(_, value)| value)
+    }
+
+    /// Scan the field offsets once, so fields can be decoded by index or
+    /// name without walking the preceding fields again
+    pub fn to_lazy(&self) -> LazyDataRecord<'a, 't> {
+        let mut offset = 0;
+        let field_bounds = self
+            .template
+            .field_specifiers()
+            .iter()
+            .map_while(|field_spec| {
+                let (start, end) = field_bounds(self.data, offset, field_spec.field_length)?;
+                offset = end;
+                Some(start..end)
+            })
+            .collect();
+        LazyDataRecord {
+            record: *self,
+            field_bounds,
+        }
+    }
+
+    /// Decode into an owned `DataRecord`
+    pub fn to_data_record(
+        &self,
+        templates: &TemplateStore,
+        formatter: &Formatter,
+    ) -> Result<DataRecord, Error> {
+        let ctx = FieldContext {
+            templates: Some(&**templates),
+            formatter: Some(formatter),
+            options: self.options,
+            ..Default::default()
+        };
+        DataRecord::read_fields(
+            &mut Cursor::new(self.data),
+            Endian::Big,
+            self.template_id,
+            self.template,
+            ctx,
+        )
+        .map_err(|e| Error::from(e).in_set(self.template_id, self.offset as u64))
+    }
+
+    /// Decode the field at `index`, whose content is `bounds` of the record
+    fn decode_field(
+        &self,
+        index: usize,
+        bounds: Range<usize>,
+    ) -> Result<DataRecordValueRef<'a>, Error> {
+        let field_spec = &self.template.field_specifiers()[index];
+        let bytes = &self.data[bounds.clone()];
+        DataRecordValueRef::decode(field_spec, bytes, self.options).map_err(|kind| Error {
+            template_id: Some(self.template_id),
+            field_index: Some(index),
+            information_element: Some((
+                field_spec.enterprise_number.unwrap_or(0),
+                field_spec.information_element_identifier,
+            )),
+            ..Error::new(kind, 0).in_set(self.template_id, (self.offset + bounds.start) as u64)
+        })
+    }
+}
+
+/// A `DataRecordRef` with known field offsets, which decodes fields only
+/// when they are requested
+#[derive(Clone, Debug)]
+pub struct LazyDataRecord<'a, 't> {
+    record: DataRecordRef<'a, 't>,
+    /// content of each field, without any length prefix
+    field_bounds: Vec<Range<usize>>,
+}
+
+impl<'a, 't> LazyDataRecord<'a, 't> {
+    pub fn record(&self) -> &DataRecordRef<'a, 't> {
+        &self.record
+    }
+
+    pub fn len(&self) -> usize {
+        self.field_bounds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.field_bounds.is_empty()
+    }
+
+    /// Field specifier of the field at `index`, in template order
+    pub fn field_specifier(&self, index: usize) -> Option<&'t ExpandedFieldSpecifier> {
+        self.record.template.field_specifiers().get(index)
+    }
+
+    /// Index of the first field for `key`
+    pub fn index_of(&self, key: &DataRecordKey) -> Option<usize> {
+        self.record
+            .template
+            .field_specifiers()
+            .iter()
+            .position(|field_spec| field_spec.name == *key)
+    }
+
+    /// Raw content of the field at `index`, without any length prefix
+    pub fn raw(&self, index: usize) -> Option<&'a [u8]> {
+        let data = self.record.data;
+        self.field_bounds
+            .get(index)
+            .map(|bounds| &data[bounds.clone()])
+    }
+
+    /// Decode the field at `index`, in template order
+    pub fn get_index(&self, index: usize) -> Option<Result<DataRecordValueRef<'a>, Error>> {
+        let bounds = self.field_bounds.get(index)?;
+        Some(self.record.decode_field(index, bounds.clone()))
+    }
+
+    /// Decode the first field for `key`
+    pub fn get(&self, key: &DataRecordKey) -> Option<Result<DataRecordValueRef<'a>, Error>> {
+        self.get_index(self.index_of(key)?)
+    }
+}
+
+pub struct FieldRefIter<'a, 't> {
+    record: DataRecordRef<'a, 't>,
+    field_specifiers: std::iter::Enumerate<std::slice::Iter<'t, ExpandedFieldSpecifier>>,
+    offset: usize,
+}
+
+impl<'a, 't> Iterator for FieldRefIter<'a, 't> {
+    type Item = (
+        &'t ExpandedFieldSpecifier,
+        Result<DataRecordValueRef<'a>, Error>,
+    );
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (index, field_spec) = self.field_specifiers.next()?;
+        // records are only created once all of their fields fit
+        let (start, end) = field_bounds(self.record.data, self.offset, field_spec.field_length)?;
+        self.offset = end;
+        Some((field_spec, self.record.decode_field(index, start..end)))
+    }
+}
+
+/// A field value borrowed from the message buffer
+#[derive(PartialEq, Clone, Debug)]
+pub enum DataRecordValueRef<'a> {
+    Bytes(&'a [u8]),
+    String(&'a str),
+    /// The undecoded content of a basicList, subTemplateList or
+    /// subTemplateMultiList, see `DataRecordRef::to_data_record`
+    /// <https://www.rfc-editor.org/rfc/rfc6313#section-4.5>
+    List(&'a [u8]),
+    /// Any other type, which are decoded without allocating
+    Value(DataRecordValue),
+}
+
+impl<'a> DataRecordValueRef<'a> {
+    /// Decode the content of a field as the owned parser would with
+    /// `options`, borrowing where it can
+    fn decode(
+        field_spec: &ExpandedFieldSpecifier,
+        bytes: &'a [u8],
+        options: ParseOptions,
+    ) -> Result<Self, IpfixError> {
+        let length = field_spec.field_length;
+        let trim = length != u16::MAX && options.trim_trailing_nul;
+        Ok(match field_spec.ty {
+            DataRecordType::Bytes => Self::Bytes(bytes),
+            DataRecordType::String => match std::str::from_utf8(bytes) {
+                Ok(s) if trim => Self::String(s.trim_end_matches('\0')),
+                Ok(s) => Self::String(s),
+                Err(e) => match options.strings {
+                    StringPolicy::Strict => return Err(IpfixError::InvalidUtf8(e)),
+                    StringPolicy::Lossy => {
+                        let mut string = String::from_utf8_lossy(bytes).into_owned();
+                        if trim {
+                            string.truncate(string.trim_end_matches('\0').len());
+                        }
+                        Self::Value(DataRecordValue::String(string))
+                    }
+                    StringPolicy::Bytes => Self::Bytes(bytes),
+                },
+            },
+            DataRecordType::BasicList
+            | DataRecordType::SubTemplateList
+            | DataRecordType::SubTemplateMultiList => Self::List(bytes),
+            // the length prefix is not part of `bytes`, and no other type
+            // has a variable length
+            ty if length == u16::MAX && options.raw_fallback => Self::Value(DataRecordValue::Raw {
+                ty,
+                bytes: bytes.to_vec(),
+            }),
+            ty if length == u16::MAX => {
+                return Err(IpfixError::InvalidFieldSpecLength { ty, length })
+            }
+            ty => Self::Value(
+                DataRecordValue::read_field(
+                    &mut Cursor::new(bytes),
+                    Endian::Big,
+                    ty,
+                    length,
+                    FieldContext {
+                        options,
+                        ..Default::default()
+                    },
+                )
+                .map_err(|e| Error::from(e).kind)?,
+            ),
+        })
+    }
+
+    /// Convert into an owned `DataRecordValue`. Lists are returned as
+    /// `DataRecordValue::Bytes`, as they need a template store to decode
+    pub fn to_value(&self) -> DataRecordValue {
+        match self {
+            Self::Bytes(b) | Self::List(b) => DataRecordValue::Bytes(b.to_vec()),
+            Self::String(s) => DataRecordValue::String((*s).to_owned()),
+            Self::Value(v) => v.clone(),
+        }
+    }
+}
+
+/// Bounds of the content of a field starting at `offset`, skipping the
+/// length prefix of variable length fields. Returns `None` if the field
+/// runs past the end of `data`
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-7>
+fn field_bounds(data: &[u8], offset: usize, field_length: u16) -> Option<(usize, usize)> {
+    let (start, length) = if field_length == u16::MAX {
+        match *data.get(offset)? {
+            255 => {
+                let ext = data.get(offset + 1..offset + 3)?;
+                (
+                    offset + 3,
+                    usize::from(u16::from_be_bytes([ext[0], ext[1]])),
+                )
+            }
+            length => (offset + 1, usize::from(length)),
+        }
+    } else {
+        (offset, usize::from(field_length))
+    };
+    let end = start + length;
+    (end <= data.len()).then_some((start, end))
+}
@@ -0,0 +1,270 @@
+//! Errors from parsing and writing IPFIX messages
+
+use crate::parser::{DataRecordKey, DataRecordType};
+
+/// What went wrong, see `Error` for where
+#[derive(derive_more::Display, Debug)]
+pub enum IpfixError {
+    #[display(fmt = "Truncated Message Header: {_0} bytes")]
+    TruncatedHeader(usize),
+    #[display(fmt = "Bad Version Number: {_0}")]
+    BadVersion(u16),
+    /// A message length shorter than the message header
+    #[display(fmt = "Bad Message Length: {_0}")]
+    BadMessageLength(u16),
+    /// A message length greater than the bytes available
+    #[display(fmt = "Truncated Message: length {length}, {available} bytes available")]
+    TruncatedMessage { length: u16, available: usize },
+    /// Bytes after the end of a message, as given by its length
+    #[display(fmt = "Trailing Bytes: {_0}")]
+    TrailingBytes(usize),
+    /// A set length shorter than the set header, or running past the end
+    /// of the message
+    #[display(fmt = "Bad Set Length: {_0}")]
+    BadSetLength(u16),
+    /// A data record running past the end of its set, or an element of a
+    /// structured data list past the end of the list
+    #[display(fmt = "Truncated Record")]
+    TruncatedRecord,
+    /// Padding at the end of a set that is not all zeros, when parsing
+    /// with `ParseOptions::strict_padding`
+    #[display(fmt = "Non-Zero Padding")]
+    NonZeroPadding,
+    #[display(fmt = "Reserved Set ID: {_0}")]
+    ReservedSetId(u16),
+    #[display(fmt = "Reserved Template ID: {_0}")]
+    ReservedTemplateId(u16),
+    #[display(fmt = "Missing Template: {_0}")]
+    MissingTemplate(u16),
+    #[display(fmt = "Missing Data: {_0:?}")]
+    MissingData(DataRecordKey),
+    /// A value the template has no field for, when writing
+    #[display(fmt = "Unused Data: {_0:?}")]
+    UnusedData(DataRecordKey),
+    /// A value whose type is not the type of its field, when writing
+    #[display(fmt = "Type Mismatch: expected {expected:?}, found {actual:?}")]
+    TypeMismatch {
+        expected: DataRecordType,
+        actual: DataRecordType,
+    },
+    /// A value that does not encode to the length of its field, when
+    /// writing. `length` is 65535 for variable length fields
+    #[display(fmt = "Field Length Mismatch: field length {length}, value length {actual}")]
+    FieldLengthMismatch { length: u16, actual: usize },
+    #[display(fmt = "Invalid Length for Field Spec: {ty:?}, {length}")]
+    InvalidFieldSpecLength { ty: DataRecordType, length: u16 },
+    #[display(fmt = "Invalid UTF-8: {_0}")]
+    InvalidUtf8(std::str::Utf8Error),
+    #[display(fmt = "Value Out of Range for {length} bytes: {value}")]
+    ValueOutOfRange { value: i128, length: u16 },
+    #[display(fmt = "Value Out of Range for {length} bytes: {value}")]
+    FloatOutOfRange { value: f64, length: u16 },
+    #[display(
+        fmt = "Invalid Scope Field Count for Options Template {template_id}: {scope_field_count} (field count {field_count})"
+    )]
+    InvalidScopeFieldCount {
+        template_id: u16,
+        scope_field_count: u16,
+        field_count: usize,
+    },
+    /// An element of a structured data list that takes up no bytes, so
+    /// the list would never end
+    #[display(fmt = "Empty List Element")]
+    EmptyListElement,
+    /// More sets, records, templates, fields, decoded bytes or nested
+    /// lists than the `ParseLimits` of the parse allow
+    #[display(fmt = "Limit Exceeded: {limit:?} (max {max})")]
+    LimitExceeded { limit: Limit, max: usize },
+    #[display(fmt = "Encoded Length Too Large: {_0}")]
+    LengthOverflow(usize),
+    #[display(fmt = "{_0}")]
+    Io(std::io::Error),
+    /// Any other failure from the underlying binrw parser, e.g. a set
+    /// that matches none of the set formats
+    #[display(fmt = "{_0}")]
+    Other(binrw::Error),
+}
+
+impl std::error::Error for IpfixError {}
+
+/// Which of the `ParseLimits` was exceeded
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Limit {
+    Sets,
+    Records,
+    Templates,
+    Fields,
+    Bytes,
+    Domains,
+    Depth,
+}
+
+impl IpfixError {
+    pub(crate) fn into_binrw_error(self, pos: u64) -> binrw::Error {
+        binrw::Error::Custom {
+            pos,
+            err: Box::new(self),
+        }
+    }
+}
+
+/// An error from a public parse or write function, with its location
+#[derive(Debug)]
+pub struct Error {
+    pub kind: IpfixError,
+    /// offset from the start of the message
+    pub offset: u64,
+    pub set_id: Option<u16>,
+    pub template_id: Option<u16>,
+    /// index of the field in its template. For fields nested in a list,
+    /// this is the list field
+    pub field_index: Option<usize>,
+    /// enterprise number (0 for IANA) and Information Element identifier
+    /// of the field
+    pub information_element: Option<(u32, u16)>,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at offset {}", self.kind, self.offset)?;
+        if let Some(set_id) = self.set_id {
+            write!(f, ", set {set_id}")?;
+        }
+        if let Some(template_id) = self.template_id {
+            write!(f, ", template {template_id}")?;
+        }
+        if let Some(field_index) = self.field_index {
+            write!(f, ", field {field_index}")?;
+        }
+        if let Some((enterprise_number, id)) = self.information_element {
+            write!(f, " ({enterprise_number}/{id})")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.kind)
+    }
+}
+
+impl Error {
+    pub(crate) fn new(kind: IpfixError, offset: u64) -> Self {
+        let template_id = match kind {
+            IpfixError::MissingTemplate(template_id) => Some(template_id),
+            _ => None,
+        };
+        Self {
+            kind,
+            offset,
+            set_id: None,
+            template_id,
+            field_index: None,
+            information_element: None,
+        }
+    }
+
+    /// Add the set to an error from reading or writing at `set_offset`,
+    /// whose offset is relative to the set
+    pub(crate) fn in_set(mut self, set_id: u16, set_offset: u64) -> Self {
+        self.offset += set_offset;
+        self.set_id.get_or_insert(set_id);
+        self
+    }
+}
+
+impl From<binrw::Error> for Error {
+    fn from(error: binrw::Error) -> Self {
+        match error {
+            binrw::Error::Backtrace(backtrace) => Self::from(*backtrace.error),
+            binrw::Error::Io(error) => Self::new(IpfixError::Io(error), 0),
+            binrw::Error::Custom { pos, err } => match err.downcast::<IpfixError>() {
+                Ok(kind) => Self::new(*kind, pos),
+                Err(err) => match err.downcast::<FieldError>() {
+                    Ok(field_error) => {
+                        let FieldError {
+                            pos,
+                            template_id,
+                            field_index,
+                            information_element,
+                            error,
+                        } = *field_error;
+                        // the outermost field is the one in the template
+                        Self {
+                            offset: pos,
+                            template_id: Some(template_id),
+                            field_index: Some(field_index),
+                            information_element: Some(information_element),
+                            ..Self::from(error)
+                        }
+                    }
+                    Err(err) => match err.downcast::<Error>() {
+                        Ok(error) => *error,
+                        Err(err) => {
+                            Self::new(IpfixError::Other(binrw::Error::Custom { pos, err }), pos)
+                        }
+                    },
+                },
+            },
+            binrw::Error::EnumErrors {
+                pos,
+                variant_errors,
+            } => {
+                // the variant that failed with an error of ours got past
+                // its pre-assertions, so is the one that was meant
+                let mut variant_errors: Vec<Self> = variant_errors
+                    .into_iter()
+                    .map(|(_, error)| Self::from(error))
+                    .collect();
+                match variant_errors
+                    .iter()
+                    .position(|error| !matches!(error.kind, IpfixError::Other(_)))
+                {
+                    Some(index) => variant_errors.swap_remove(index),
+                    None => Self::new(IpfixError::Other(binrw::Error::NoVariantMatch { pos }), pos),
+                }
+            }
+            error => {
+                let pos = match &error {
+                    binrw::Error::BadMagic { pos, .. }
+                    | binrw::Error::AssertFail { pos, .. }
+                    | binrw::Error::NoVariantMatch { pos } => *pos,
+                    _ => 0,
+                };
+                Self::new(IpfixError::Other(error), pos)
+            }
+        }
+    }
+}
+
+impl From<Error> for binrw::Error {
+    fn from(error: Error) -> Self {
+        binrw::Error::Custom {
+            pos: error.offset,
+            err: Box::new(error),
+        }
+    }
+}
+
+/// The field an error occurred in, carried through binrw until it is
+/// converted to an `Error`
+#[derive(derive_more::Display, Debug)]
+#[display(fmt = "{error}")]
+pub(crate) struct FieldError {
+    /// start of the field
+    pub pos: u64,
+    pub template_id: u16,
+    pub field_index: usize,
+    pub information_element: (u32, u16),
+    pub error: binrw::Error,
+}
+
+impl FieldError {
+    pub(crate) fn into_binrw_error(self) -> binrw::Error {
+        binrw::Error::Custom {
+            pos: self.pos,
+            err: Box::new(self),
+        }
+    }
+}
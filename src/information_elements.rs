@@ -10,7 +10,7 @@ pub type Formatter = HashMap<(u32, u16), (&'static str, DataRecordType)>;
 macro_rules! formatter {
     { $(($key:expr, $id:expr) => ($string:expr, $value:ident)),+ $(,)? } => {
         HashMap::from_iter([
-            $( ((($key, $id), ($string, DataRecordType::$value))), )+
+            $( (($key, $id), ($string, DataRecordType::$value)), )+
         ])
     };
 }
@@ -20,7 +20,7 @@ macro_rules! formatter {
 macro_rules! extend_formatter(
     { $formatter:ident += { $(($key:expr, $id:expr) => ($string:expr, $value:ident)),+ $(,)? } } => {
         $formatter.extend([
-            $( ((($key, $id), ($string, DataRecordType::$value))), )+
+            $( (($key, $id), ($string, DataRecordType::$value)), )+
         ])
     };
 );
@@ -1,22 +1,276 @@
 #![doc = include_str!("../README.md")]
 
+pub mod borrowed;
+pub mod error;
 pub mod information_elements;
 pub mod parser;
+pub mod pending;
+pub mod resolver;
 pub mod template_store;
+pub mod timestamp;
 mod util;
+pub mod visitor;
 
-use std::{io::Cursor, rc::Rc};
+use std::{hash::Hash, io::Cursor, rc::Rc};
 
-use binrw::{BinRead, BinResult};
+use binrw::BinRead;
+use error::{Error, IpfixError, Limit};
 use information_elements::Formatter;
-use template_store::TemplateStore;
+use template_store::{ScopedTemplateStore, TemplateStore};
+use util::Allocation;
 
-use crate::parser::Message;
+use crate::parser::{Message, MessageHeader, ParseOptions, Set};
 
 pub fn parse_ipfix_message<T: AsRef<[u8]>>(
     buf: &T,
     templates: TemplateStore,
     formatter: Rc<Formatter>,
-) -> BinResult<Message> {
-    Message::read_args(&mut Cursor::new(buf), (templates, formatter))
+) -> Result<Message, Error> {
+    parse_ipfix_message_with_options(buf, templates, formatter, ParseOptions::default())
+}
+
+pub fn parse_ipfix_message_with_options<T: AsRef<[u8]>>(
+    buf: &T,
+    templates: TemplateStore,
+    formatter: Rc<Formatter>,
+    options: ParseOptions,
+) -> Result<Message, Error> {
+    read_message(buf.as_ref(), templates, formatter, options, &mut |error| {
+        Err(error)
+    })
+}
+
+/// Parse a message, skipping any sets that fail to decode instead of
+/// failing the whole message. Returns the message with the sets that were
+/// decoded, and the errors for those that were not
+pub fn parse_ipfix_message_lenient<T: AsRef<[u8]>>(
+    buf: &T,
+    templates: TemplateStore,
+    formatter: Rc<Formatter>,
+) -> Result<(Message, Vec<Error>), Error> {
+    parse_ipfix_message_lenient_with_options(buf, templates, formatter, ParseOptions::default())
+}
+
+pub fn parse_ipfix_message_lenient_with_options<T: AsRef<[u8]>>(
+    buf: &T,
+    templates: TemplateStore,
+    formatter: Rc<Formatter>,
+    options: ParseOptions,
+) -> Result<(Message, Vec<Error>), Error> {
+    let mut errors = Vec::new();
+    let msg = read_message(buf.as_ref(), templates, formatter, options, &mut |error| {
+        errors.push(error);
+        Ok(())
+    })?;
+    Ok((msg, errors))
+}
+
+/// Parse the messages in a buffer holding several, one after another, e.g.
+/// read from a file or a TCP stream. Each message fails on its own, except
+/// when its header is invalid or it is truncated, which ends the iteration
+/// as the next message cannot be found. Error offsets are from the start of
+/// each message
+pub fn parse_ipfix_messages<'a, T: AsRef<[u8]>>(
+    buf: &'a T,
+    templates: TemplateStore,
+    formatter: Rc<Formatter>,
+) -> impl Iterator<Item = Result<Message, Error>> + 'a {
+    parse_ipfix_messages_with_options(buf, templates, formatter, ParseOptions::default())
+}
+
+pub fn parse_ipfix_messages_with_options<'a, T: AsRef<[u8]>>(
+    buf: &'a T,
+    templates: TemplateStore,
+    formatter: Rc<Formatter>,
+    options: ParseOptions,
+) -> impl Iterator<Item = Result<Message, Error>> + 'a {
+    split_messages(buf.as_ref()).map(move |message| {
+        let (_, message) = message?;
+        read_message(
+            message,
+            templates.clone(),
+            formatter.clone(),
+            options,
+            &mut |error| Err(error),
+        )
+    })
+}
+
+/// Parse a message with the templates of `session` and the message's
+/// Observation Domain, see `ScopedTemplateStore`
+pub fn parse_ipfix_message_scoped<T: AsRef<[u8]>, K: Hash + Eq + Clone + 'static>(
+    buf: &T,
+    templates: &ScopedTemplateStore<K>,
+    session: &K,
+    formatter: Rc<Formatter>,
+) -> Result<Message, Error> {
+    parse_ipfix_message_scoped_with_options(
+        buf,
+        templates,
+        session,
+        formatter,
+        ParseOptions::default(),
+    )
+}
+
+pub fn parse_ipfix_message_scoped_with_options<T: AsRef<[u8]>, K: Hash + Eq + Clone + 'static>(
+    buf: &T,
+    templates: &ScopedTemplateStore<K>,
+    session: &K,
+    formatter: Rc<Formatter>,
+    options: ParseOptions,
+) -> Result<Message, Error> {
+    let header = MessageHeader::from_bytes(buf.as_ref())?;
+    templates.parse_in_scope(session, &header, options.limits, |templates| {
+        parse_ipfix_message_with_options(buf, templates, formatter, options)
+    })
+}
+
+/// `parse_ipfix_messages`, with the templates of `session` and each
+/// message's Observation Domain
+pub fn parse_ipfix_messages_scoped<'a, T: AsRef<[u8]>, K: Hash + Eq + Clone + 'static>(
+    buf: &'a T,
+    templates: &'a ScopedTemplateStore<K>,
+    session: &'a K,
+    formatter: Rc<Formatter>,
+) -> impl Iterator<Item = Result<Message, Error>> + 'a {
+    parse_ipfix_messages_scoped_with_options(
+        buf,
+        templates,
+        session,
+        formatter,
+        ParseOptions::default(),
+    )
+}
+
+pub fn parse_ipfix_messages_scoped_with_options<
+    'a,
+    T: AsRef<[u8]>,
+    K: Hash + Eq + Clone + 'static,
+>(
+    buf: &'a T,
+    templates: &'a ScopedTemplateStore<K>,
+    session: &'a K,
+    formatter: Rc<Formatter>,
+    options: ParseOptions,
+) -> impl Iterator<Item = Result<Message, Error>> + 'a {
+    split_messages(buf.as_ref()).map(move |message| {
+        let (header, message) = message?;
+        templates.parse_in_scope(session, &header, options.limits, |templates| {
+            read_message(
+                message,
+                templates,
+                formatter.clone(),
+                options,
+                &mut |error| Err(error),
+            )
+        })
+    })
+}
+
+/// Split a buffer into its messages by their header lengths, stopping after
+/// a header that is invalid or a message that is truncated
+fn split_messages(buf: &[u8]) -> impl Iterator<Item = Result<(MessageHeader, &[u8]), Error>> {
+    let mut rest = buf;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let header = match MessageHeader::from_bytes(rest) {
+            Ok(header) => header,
+            Err(error) => {
+                rest = &[];
+                return Some(Err(error));
+            }
+        };
+        let (message, next) = rest.split_at(usize::from(header.length));
+        rest = next;
+        Some(Ok((header, message)))
+    })
+}
+
+/// Read a message set by set. Errors in a set are passed to `on_error`,
+/// which decides whether to continue with the next set
+fn read_message(
+    buf: &[u8],
+    templates: TemplateStore,
+    formatter: Rc<Formatter>,
+    options: ParseOptions,
+    on_error: &mut dyn FnMut(Error) -> Result<(), Error>,
+) -> Result<Message, Error> {
+    let header = MessageHeader::from_bytes(buf)?;
+    let length = usize::from(header.length);
+    if buf.len() > length {
+        on_error(Error::new(
+            IpfixError::TrailingBytes(buf.len() - length),
+            length as u64,
+        ))?;
+    }
+    let buf = &buf[..length];
+
+    let limits = options.limits;
+    let mut sets = Vec::new();
+    let mut bytes = 0;
+    let mut offset = MessageHeader::SIZE;
+    while offset < buf.len() {
+        let set_offset = offset as u64;
+        if sets.len() == limits.max_sets {
+            let limit = IpfixError::LimitExceeded {
+                limit: Limit::Sets,
+                max: limits.max_sets,
+            };
+            on_error(Error::new(limit, set_offset))?;
+            break;
+        }
+        let (set_id, length) = match buf.get(offset..offset + 4) {
+            Some(set_header) => (
+                u16::from_be_bytes([set_header[0], set_header[1]]),
+                u16::from_be_bytes([set_header[2], set_header[3]]),
+            ),
+            None => {
+                // too short for even a set header
+                on_error(Error::new(IpfixError::BadSetLength(0), set_offset))?;
+                break;
+            }
+        };
+
+        // without a usable length, there is no way to find the next set
+        if length <= 4 || offset + usize::from(length) > buf.len() {
+            on_error(Error::new(IpfixError::BadSetLength(length), 0).in_set(set_id, set_offset))?;
+            break;
+        }
+        let set_bytes = &buf[offset..offset + usize::from(length)];
+        offset += usize::from(length);
+
+        if set_id < 2 || (4..=255).contains(&set_id) {
+            on_error(Error::new(IpfixError::ReservedSetId(set_id), 0).in_set(set_id, set_offset))?;
+            continue;
+        }
+
+        match Set::read_args(
+            &mut Cursor::new(set_bytes),
+            (templates.clone(), formatter.clone(), options),
+        ) {
+            Ok(set) => {
+                bytes += set.allocated_bytes();
+                if bytes > limits.max_bytes {
+                    let limit = IpfixError::LimitExceeded {
+                        limit: Limit::Bytes,
+                        max: limits.max_bytes,
+                    };
+                    on_error(Error::new(limit, 0).in_set(set_id, set_offset))?;
+                    break;
+                }
+                sets.push(set);
+            }
+            Err(error) => on_error(Error::from(error).in_set(set_id, set_offset))?,
+        }
+    }
+
+    Ok(Message {
+        export_time: header.export_time,
+        sequence_number: header.sequence_number,
+        observation_domain_id: header.observation_domain_id,
+        sets,
+    })
 }
@@ -1,68 +1,232 @@
 //! IPFIX reader/writer
 
 use std::{
+    borrow::Cow,
     net::{Ipv4Addr, Ipv6Addr},
     rc::Rc,
 };
 
-use ahash::{HashMap, HashMapExt};
 use binrw::{
-    binrw, binwrite, count,
-    io::{Read, Seek, Write},
-    until_eof, BinRead, BinReaderExt, BinResult, BinWrite, BinWriterExt, Endian,
+    binread, binrw, count,
+    io::{Cursor, Read, Seek, SeekFrom, TakeSeekExt, Write},
+    meta::{EndianKind, ReadEndian, WriteEndian},
+    BinRead, BinReaderExt, BinResult, BinWrite, BinWriterExt, Endian,
 };
 
+pub use crate::error::IpfixError;
+use crate::error::{Error, FieldError, Limit};
 use crate::information_elements::Formatter;
-use crate::template_store::{Template, TemplateStore};
-use crate::util::{stream_position, until_limit, write_position_at};
+use crate::template_store::{ExpandedFieldSpecifier, Template, TemplateStorage, TemplateStore};
+use crate::timestamp::NtpTimestamp;
+use crate::util::{position, until_limit, Allocation};
 
-#[derive(derive_more::Display, Debug)]
-pub enum IpfixError {
-    #[display(fmt = "Missing Template")]
-    MissingTemplate(u16),
-    #[display(fmt = "Missing Data: {_0:?}")]
-    MissingData(DataRecordKey),
-    #[display(fmt = "Invalid Length for Field Spec: {ty:?}, {length}")]
-    InvalidFieldSpecLength { ty: DataRecordType, length: u16 },
-}
-
-impl std::error::Error for IpfixError {}
-
-impl IpfixError {
-    pub(crate) fn into_binrw_error(self, pos: u64) -> binrw::Error {
-        binrw::Error::Custom {
-            pos,
-            err: Box::new(self),
-        }
-    }
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-3.1>
+#[derive(PartialEq, Clone, Debug)]
+pub struct Message {
+    pub export_time: u32,
+    pub sequence_number: u32,
+    pub observation_domain_id: u32,
+    pub sets: Vec<Set>,
 }
 
+/// The fixed-size header of a `Message`
 /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.1>
 #[binrw]
 #[brw(big, magic = 10u16)]
-#[br(import( templates: TemplateStore, formatter: Rc<Formatter>))]
-#[bw(import( templates: TemplateStore, formatter: Rc<Formatter>, alignment: u8))]
-#[bw(stream = s)]
-#[derive(PartialEq, Clone, Debug)]
-pub struct Message {
-    #[br(temp)]
-    // store offset for later updating
-    #[bw(try_calc = stream_position(s))]
-    length: u16,
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct MessageHeader {
+    pub length: u16,
     pub export_time: u32,
     pub sequence_number: u32,
     pub observation_domain_id: u32,
-    #[br(parse_with = until_eof)]
-    #[br(args(templates, formatter))]
-    #[bw(args(templates, formatter, alignment))]
-    pub sets: Vec<Set>,
-    // jump back to length and set by current position
-    #[br(temp)]
-    #[bw(restore_position, try_calc = write_position_at(s, length, 0))]
-    _temp: (),
+}
+
+impl MessageHeader {
+    /// Encoded size in bytes, including the version number
+    pub const SIZE: usize = 16;
+
+    /// Read the header at the start of `buf`, checking that `buf` holds
+    /// the whole message. It may be followed by other bytes, e.g. the next
+    /// message
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < Self::SIZE {
+            return Err(Error::new(IpfixError::TruncatedHeader(buf.len()), 0));
+        }
+        let version = u16::from_be_bytes([buf[0], buf[1]]);
+        if version != 10 {
+            return Err(Error::new(IpfixError::BadVersion(version), 0));
+        }
+        let header = Self::read(&mut Cursor::new(buf))?;
+        if usize::from(header.length) < Self::SIZE {
+            return Err(Error::new(IpfixError::BadMessageLength(header.length), 2));
+        }
+        if usize::from(header.length) > buf.len() {
+            return Err(Error::new(
+                IpfixError::TruncatedMessage {
+                    length: header.length,
+                    available: buf.len(),
+                },
+                2,
+            ));
+        }
+        Ok(header)
+    }
+}
+
+/// Options for a parse, see `parse_ipfix_message_with_options`
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct ParseOptions {
+    /// how to decode string fields that are not valid UTF-8
+    pub strings: StringPolicy,
+    /// remove trailing NUL padding from fixed length string fields that
+    /// decode as strings
+    pub trim_trailing_nul: bool,
+    /// decode fields whose length does not fit their type as
+    /// `DataRecordValue::Raw`, instead of failing with
+    /// `IpfixError::InvalidFieldSpecLength`
+    pub raw_fallback: bool,
+    /// limits on what the parse accepts, for untrusted input
+    pub limits: ParseLimits,
+    /// fail on set padding that is not all zeros, with
+    /// `IpfixError::NonZeroPadding`
+    pub strict_padding: bool,
+    /// accept options templates with a scope field count of 0, which
+    /// RFC 7011 forbids but some exporters send. Their records have no
+    /// scope fields
+    pub allow_zero_scope_field_count: bool,
+}
+
+/// Limits on a parse, so that hostile input cannot make it allocate without
+/// bound. Exceeding one fails with `IpfixError::LimitExceeded`
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ParseLimits {
+    /// sets in a message
+    pub max_sets: usize,
+    /// records in a set, or elements in a structured data list
+    pub max_records: usize,
+    /// templates and options templates in the template store, or in all
+    /// of the scopes of a session in a `ScopedTemplateStore`
+    pub max_templates: usize,
+    /// fields in a template or options template
+    pub max_fields: usize,
+    /// estimated size of the decoded sets of a message, in bytes
+    pub max_bytes: usize,
+    /// Observation Domains of a session in a `ScopedTemplateStore`
+    pub max_domains: usize,
+    /// structured data lists nested in each other, counting the list in
+    /// the record itself, so 0 allows no lists
+    pub max_depth: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_sets: 1024,
+            max_records: 1 << 16,
+            max_templates: 4096,
+            max_fields: 1024,
+            max_bytes: 64 << 20,
+            max_domains: 64,
+            max_depth: 16,
+        }
+    }
+}
+
+/// What to do with a string field that is not valid UTF-8
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum StringPolicy {
+    /// fail with `IpfixError::InvalidUtf8`
+    #[default]
+    Strict,
+    /// replace invalid sequences with U+FFFD
+    Lossy,
+    /// keep the field as `DataRecordValue::Bytes`
+    Bytes,
+}
+
+/// Options for writing, see `Message::to_bytes_with_options`
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct WriteOptions {
+    /// sets without recorded `padding` are padded to a multiple of this
+    /// many bytes. 0 and 1 mean no padding
+    pub alignment: u8,
+    /// how closely data records must match their templates
+    pub validation: Validation,
+}
+
+/// How data records are checked against their template before they are
+/// written. Values that do not encode to their field's length, e.g. a
+/// `U64` in a 2 byte field, are always an error, as they would corrupt
+/// the set
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Validation {
+    /// also fail on values whose type does not match the template
+    /// (`IpfixError::TypeMismatch`) and on values the template has no
+    /// field for (`IpfixError::UnusedData`)
+    #[default]
+    Strict,
+    Permissive,
 }
 
 impl Message {
+    /// Encode the message. Sets without recorded `padding`, e.g. those
+    /// built by hand, are padded to a multiple of `alignment` bytes
+    pub fn to_bytes(
+        &self,
+        templates: TemplateStore,
+        formatter: Rc<Formatter>,
+        alignment: u8,
+    ) -> Result<Vec<u8>, Error> {
+        let options = WriteOptions {
+            alignment,
+            ..Default::default()
+        };
+        self.to_bytes_with_options(templates, formatter, options)
+    }
+
+    /// Encode the message, see `WriteOptions`. Nothing is returned unless
+    /// every record is valid
+    pub fn to_bytes_with_options(
+        &self,
+        templates: TemplateStore,
+        formatter: Rc<Formatter>,
+        options: WriteOptions,
+    ) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::new();
+        MessageHeader {
+            // filled in once the sets are encoded
+            length: 0,
+            export_time: self.export_time,
+            sequence_number: self.sequence_number,
+            observation_domain_id: self.observation_domain_id,
+        }
+        .write(&mut Cursor::new(&mut buf))?;
+        for set in &self.sets {
+            let set_bytes = set
+                .to_bytes_with_options(templates.clone(), formatter.clone(), options)
+                .map_err(|e| e.in_set(set.records.set_id(), buf.len() as u64))?;
+            buf.extend(set_bytes);
+        }
+
+        let length = encoded_length(buf.len())?;
+        buf[2..4].copy_from_slice(&length.to_be_bytes());
+        Ok(buf)
+    }
+
+    /// Encode the message to a writer that does not need to implement `Seek`
+    pub fn write_to<W: std::io::Write>(
+        &self,
+        writer: &mut W,
+        templates: TemplateStore,
+        formatter: Rc<Formatter>,
+        alignment: u8,
+    ) -> Result<(), Error> {
+        let bytes = self.to_bytes(templates, formatter, alignment)?;
+        writer
+            .write_all(&bytes)
+            .map_err(|e| Error::new(IpfixError::Io(e), 0))
+    }
+
     pub fn iter_template_records(&self) -> impl Iterator<Item = &TemplateRecord> {
         self.sets
             .iter()
@@ -83,6 +247,18 @@ impl Message {
             .flatten()
     }
 
+    pub fn iter_template_withdrawal_records(
+        &self,
+    ) -> impl Iterator<Item = &TemplateWithdrawalRecord> {
+        self.sets
+            .iter()
+            .filter_map(|set| match &set.records {
+                Records::TemplateWithdrawal { withdrawals, .. } => Some(withdrawals),
+                _ => None,
+            })
+            .flatten()
+    }
+
     pub fn iter_data_records(&self) -> impl Iterator<Item = &DataRecord> {
         self.sets
             .iter()
@@ -95,59 +271,271 @@ impl Message {
 }
 
 /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.3>
-#[binrw]
-#[br(big, import( templates: TemplateStore, formatter: Rc<Formatter> ))]
-#[bw(big, stream = s, import( templates: TemplateStore, formatter: Rc<Formatter>, alignment: u8 ))]
+#[binread]
+#[br(big, import( templates: TemplateStore, formatter: Rc<Formatter>, options: ParseOptions ))]
 #[derive(PartialEq, Clone, Debug)]
 pub struct Set {
     #[br(temp)]
-    #[bw(calc = records.set_id())]
     set_id: u16,
     #[br(temp)]
-    #[br(assert(length > 4, "invalid set length: [{length} <= 4]"))]
-    // store offset for later updating
-    #[bw(try_calc = stream_position(s))]
+    #[br(assert(length > 4, IpfixError::BadSetLength(length)))]
     length: u16,
-    #[br(pad_size_to = length - 4)]
-    #[br(args(set_id, length - 4, templates, formatter))]
-    #[bw(align_after = alignment)]
-    #[bw(args(templates, formatter))]
+    #[br(temp, parse_with = position)]
+    records_start: u64,
+    #[br(args(set_id, length - 4, templates, formatter, options))]
     pub records: Records,
-    // jump back to length and set by current position
-    #[br(temp)]
-    #[bw(restore_position, try_calc = write_position_at(s, length, length - 2))]
-    _temp: (),
+    #[br(temp, parse_with = position)]
+    records_end: u64,
+    /// bytes after the last record, as read. When `None`, the set is
+    /// padded to the `alignment` given when it is written
+    #[br(parse_with = read_padding, args(length, records_end - records_start, options.strict_padding))]
+    pub padding: Option<Vec<u8>>,
+}
+
+/// Read the rest of a set of `set_length` bytes, after `records_length`
+/// bytes of records
+fn read_padding<R: Read + Seek>(
+    reader: &mut R,
+    endian: Endian,
+    (set_length, records_length, strict): (u16, u64, bool),
+) -> BinResult<Option<Vec<u8>>> {
+    let pos = reader.stream_position()?;
+    let length = u64::from(set_length - 4).saturating_sub(records_length);
+    let padding: Vec<u8> = count(length as usize)(reader, endian, ())?;
+    check_padding(&padding, pos, strict)?;
+    Ok(Some(padding))
+}
+
+/// Padding should be zeros
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-3.3.1>
+fn check_padding(padding: &[u8], pos: u64, strict: bool) -> BinResult<()> {
+    if strict && padding.iter().any(|&b| b != 0) {
+        return Err(IpfixError::NonZeroPadding.into_binrw_error(pos));
+    }
+    Ok(())
+}
+
+impl Set {
+    /// Encode the set, followed by its `padding`, or if it has none padded
+    /// to a multiple of `alignment` bytes. Error offsets are relative to
+    /// the start of the set
+    pub fn to_bytes(
+        &self,
+        templates: TemplateStore,
+        formatter: Rc<Formatter>,
+        alignment: u8,
+    ) -> Result<Vec<u8>, Error> {
+        let options = WriteOptions {
+            alignment,
+            ..Default::default()
+        };
+        self.to_bytes_with_options(templates, formatter, options)
+    }
+
+    /// Encode the set, see `WriteOptions`
+    pub fn to_bytes_with_options(
+        &self,
+        templates: TemplateStore,
+        formatter: Rc<Formatter>,
+        options: WriteOptions,
+    ) -> Result<Vec<u8>, Error> {
+        let mut writer = Cursor::new(Vec::new());
+        writer.write_be(&self.records.set_id())?;
+        // the length is filled in once the records are encoded
+        writer.write_be(&0u16)?;
+        self.records
+            .write_be_args(&mut writer, (templates, formatter, options.validation))?;
+
+        let mut buf = writer.into_inner();
+        if let Some(padding) = &self.padding {
+            buf.extend(padding);
+        } else if options.alignment > 1 {
+            let padding = buf.len().next_multiple_of(options.alignment.into()) - buf.len();
+            buf.resize(buf.len() + padding, 0);
+        }
+        let length = encoded_length(buf.len())?;
+        buf[2..4].copy_from_slice(&length.to_be_bytes());
+        Ok(buf)
+    }
+
+    /// Encode the set to a writer that does not need to implement `Seek`
+    pub fn write_to<W: std::io::Write>(
+        &self,
+        writer: &mut W,
+        templates: TemplateStore,
+        formatter: Rc<Formatter>,
+        alignment: u8,
+    ) -> Result<(), Error> {
+        let bytes = self.to_bytes(templates, formatter, alignment)?;
+        writer
+            .write_all(&bytes)
+            .map_err(|e| Error::new(IpfixError::Io(e), 0))
+    }
+}
+
+impl ReadEndian for Message {
+    const ENDIAN: EndianKind = EndianKind::Endian(Endian::Big);
+}
+
+/// Reads one message, checked as by `parse_ipfix_message_with_options`,
+/// leaving the reader after it, e.g. at the next message
+impl BinRead for Message {
+    type Args<'a> = (TemplateStore, Rc<Formatter>, ParseOptions);
+
+    fn read_options<R: Read + Seek>(
+        reader: &mut R,
+        _endian: Endian,
+        (templates, formatter, options): Self::Args<'_>,
+    ) -> BinResult<Self> {
+        // the version number and length, then the rest of the message, so
+        // that a short read is reported as a truncated header or message
+        let mut buf = Vec::new();
+        reader.take(4).read_to_end(&mut buf)?;
+        if let [_, _, high, low] = buf[..] {
+            let length = u16::from_be_bytes([high, low]).max(MessageHeader::SIZE as u16);
+            reader.take(u64::from(length) - 4).read_to_end(&mut buf)?;
+        }
+        Ok(crate::read_message(
+            &buf, templates, formatter, options, &mut Err,
+        )?)
+    }
+}
+
+impl WriteEndian for Message {
+    const ENDIAN: EndianKind = EndianKind::Endian(Endian::Big);
+}
+
+impl BinWrite for Message {
+    type Args<'a> = (TemplateStore, Rc<Formatter>, u8);
+
+    fn write_options<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        _endian: Endian,
+        (templates, formatter, alignment): Self::Args<'_>,
+    ) -> BinResult<()> {
+        Ok(self.write_to(writer, templates, formatter, alignment)?)
+    }
+}
+
+impl WriteEndian for Set {
+    const ENDIAN: EndianKind = EndianKind::Endian(Endian::Big);
+}
+
+impl BinWrite for Set {
+    type Args<'a> = (TemplateStore, Rc<Formatter>, u8);
+
+    fn write_options<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        _endian: Endian,
+        (templates, formatter, alignment): Self::Args<'_>,
+    ) -> BinResult<()> {
+        Ok(self.write_to(writer, templates, formatter, alignment)?)
+    }
+}
+
+impl Allocation for Set {
+    fn allocated_bytes(&self) -> usize {
+        let records = match &self.records {
+            Records::Template(records) => records.iter().map(|r| r.allocated_bytes()).sum(),
+            Records::OptionsTemplate(records) => records.iter().map(|r| r.allocated_bytes()).sum(),
+            Records::TemplateWithdrawal { withdrawals, .. } => {
+                withdrawals.iter().map(|r| r.allocated_bytes()).sum()
+            }
+            Records::Data { data, .. } => data.iter().map(|r| r.allocated_bytes()).sum(),
+            Records::Pending { content, .. } => content.len(),
+        };
+        size_of::<Self>() + records + self.padding.as_ref().map_or(0, Vec::len)
+    }
+}
+
+/// Lengths in message and set headers are 16 bit
+fn encoded_length(length: usize) -> Result<u16, Error> {
+    u16::try_from(length).map_err(|_| Error::new(IpfixError::LengthOverflow(length), 0))
 }
 
 /// <https://www.rfc-editor.org/rfc/rfc7011.html#section-3.4>
 #[binrw]
 #[brw(big)]
-#[br(import ( set_id: u16, length: u16, templates: TemplateStore, formatter: Rc<Formatter> ))]
-#[bw(import ( templates: TemplateStore, formatter: Rc<Formatter> ))]
+#[br(import ( set_id: u16, length: u16, templates: TemplateStore, formatter: Rc<Formatter>, options: ParseOptions ))]
+#[bw(import ( templates: TemplateStore, formatter: Rc<Formatter>, validation: Validation ))]
 #[derive(PartialEq, Clone, Debug)]
 pub enum Records {
     #[br(pre_assert(set_id == 2))]
     Template(
-        #[br(map = |x: Vec<TemplateRecord>| {templates.insert_template_records(x.as_slice(), &formatter); x})]
-        #[br(parse_with = until_limit(length.into()))]
+        #[br(try_map = |x: Vec<TemplateRecord>| templates.insert_template_records(x.as_slice(), &formatter, options.limits).map(|_| x))]
+        #[br(parse_with = until_limit(length.into(), (Limit::Records, options.limits.max_records), options.limits.max_bytes))]
         Vec<TemplateRecord>,
     ),
     #[br(pre_assert(set_id == 3))]
     OptionsTemplate(
-        #[br(map = |x: Vec<OptionsTemplateRecord>| {templates.insert_options_template_records(x.as_slice(), &formatter); x})]
-        #[br(parse_with = until_limit(length.into()))]
+        #[br(try_map = |x: Vec<OptionsTemplateRecord>| templates.insert_options_template_records(x.as_slice(), &formatter, options).map(|_| x))]
+        #[br(parse_with = until_limit(length.into(), (Limit::Records, options.limits.max_records), options.limits.max_bytes))]
         Vec<OptionsTemplateRecord>,
     ),
+    #[br(pre_assert(set_id == 2 || set_id == 3))]
+    TemplateWithdrawal {
+        #[br(calc = set_id)]
+        #[bw(ignore)]
+        set_id: u16,
+        #[br(map = |x: Vec<TemplateWithdrawalRecord>| {templates.withdraw_template_records(set_id, x.as_slice()); x})]
+        #[br(parse_with = until_limit(length.into(), (Limit::Records, options.limits.max_records), options.limits.max_bytes))]
+        #[br(args(set_id))]
+        withdrawals: Vec<TemplateWithdrawalRecord>,
+    },
     #[br(pre_assert(set_id > 255, "Set IDs 0-1 and 4-255 are reserved [set_id: {set_id}]"))]
     Data {
         #[br(calc = set_id)]
         #[bw(ignore)]
         set_id: u16,
-        #[br(parse_with = until_limit(length.into()))]
-        #[br(args(set_id, templates))]
-        #[bw(args(*set_id, templates))]
+        #[br(parse_with = read_data_records)]
+        #[br(args(set_id, length, templates.clone(), formatter.clone(), options))]
+        #[bw(args(*set_id, templates, validation))]
         data: Vec<DataRecord>,
     },
+    /// A data set whose template has not arrived yet, held by the template
+    /// store until it does (see `TemplateStorage::buffer_data_set`)
+    #[br(pre_assert(set_id > 255 && templates.get_template(set_id).is_none()))]
+    Pending {
+        #[br(calc = set_id)]
+        #[bw(ignore)]
+        set_id: u16,
+        #[br(try_map = |x: Vec<u8>| templates.buffer_data_set(set_id, &x, &formatter, options).map(|_| x))]
+        #[br(parse_with = count(length.into()))]
+        content: Vec<u8>,
+    },
+}
+
+/// Read the records of a data set with `length` bytes of content, leaving
+/// its padding
+fn read_data_records<R: Read + Seek>(
+    reader: &mut R,
+    endian: Endian,
+    (set_id, length, templates, formatter, options): (
+        u16,
+        u16,
+        TemplateStore,
+        Rc<Formatter>,
+        ParseOptions,
+    ),
+) -> BinResult<Vec<DataRecord>> {
+    let ctx = FieldContext {
+        templates: Some(&*templates),
+        formatter: Some(&formatter),
+        options,
+        ..Default::default()
+    };
+    let start = reader.stream_position()?;
+    let template = ctx.get_template(set_id, start)?;
+    DataRecord::read_set_records(
+        &mut reader.take_seek(length.into()),
+        endian,
+        set_id,
+        &template,
+        start + u64::from(length),
+        ctx,
+    )
 }
 
 impl Records {
@@ -155,7 +543,9 @@ impl Records {
         match self {
             Self::Template(_) => 2,
             Self::OptionsTemplate(_) => 3,
+            Self::TemplateWithdrawal { set_id, .. } => *set_id,
             Self::Data { set_id, data: _ } => *set_id,
+            Self::Pending { set_id, .. } => *set_id,
         }
     }
 }
@@ -164,13 +554,14 @@ impl Records {
 #[binrw]
 #[brw(big)]
 #[derive(PartialEq, Clone, Debug)]
-#[br(assert(template_id > 255, "Template IDs 0-255 are reserved [template_id: {template_id}]"))]
+#[br(assert(template_id > 255, IpfixError::ReservedTemplateId(template_id)))]
 pub struct TemplateRecord {
     pub template_id: u16,
     #[br(temp)]
+    #[br(assert(field_count > 0, "Template Withdrawal, not Template [template_id: {template_id}]"))]
     #[bw(try_calc = field_specifiers.len().try_into())]
     field_count: u16,
-    #[br(count = field_count)]
+    #[br(parse_with = count(field_count.into()))]
     pub field_specifiers: Vec<FieldSpecifier>,
 }
 
@@ -178,18 +569,67 @@ pub struct TemplateRecord {
 #[binrw]
 #[brw(big)]
 #[derive(PartialEq, Clone, Debug)]
-#[br(assert(template_id > 255, "Template IDs 0-255 are reserved [template_id: {template_id}]"))]
+#[br(assert(template_id > 255, IpfixError::ReservedTemplateId(template_id)))]
 pub struct OptionsTemplateRecord {
     pub template_id: u16,
     #[br(temp)]
+    #[br(assert(field_count > 0, "Template Withdrawal, not Options Template [template_id: {template_id}]"))]
     #[bw(try_calc = field_specifiers.len().try_into())]
     field_count: u16,
-    // TODO
+    /// the first `scope_field_count` field specifiers are scope fields
     pub scope_field_count: u16,
-    #[br(count = field_count)]
+    #[br(parse_with = count(field_count.into()))]
     pub field_specifiers: Vec<FieldSpecifier>,
 }
 
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-8.1>
+///
+/// A `template_id` equal to the set id (2 for Templates, 3 for Options
+/// Templates) withdraws all templates of that kind
+#[binrw]
+#[brw(big)]
+#[br(import( set_id: u16 ))]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[br(assert(template_id > 255 || template_id == set_id, IpfixError::ReservedTemplateId(template_id)))]
+pub struct TemplateWithdrawalRecord {
+    pub template_id: u16,
+    #[br(temp)]
+    #[br(assert(field_count == 0, "Template, not Template Withdrawal [template_id: {template_id}]"))]
+    #[bw(calc = 0)]
+    field_count: u16,
+}
+
+impl Allocation for TemplateRecord {
+    fn allocated_bytes(&self) -> usize {
+        size_of::<Self>() + self.field_specifiers.len() * size_of::<FieldSpecifier>()
+    }
+}
+
+impl Allocation for OptionsTemplateRecord {
+    fn allocated_bytes(&self) -> usize {
+        size_of::<Self>() + self.field_specifiers.len() * size_of::<FieldSpecifier>()
+    }
+}
+
+impl Allocation for TemplateWithdrawalRecord {
+    fn allocated_bytes(&self) -> usize {
+        size_of::<Self>()
+    }
+}
+
+impl TemplateWithdrawalRecord {
+    /// Withdraw all Templates (`set_id` 2) or all Options Templates (`set_id` 3)
+    pub fn all(set_id: u16) -> Self {
+        Self {
+            template_id: set_id,
+        }
+    }
+
+    pub fn withdraws_all(&self) -> bool {
+        self.template_id == 2 || self.template_id == 3
+    }
+}
+
 /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.2>
 #[binrw]
 #[brw(big)]
@@ -221,9 +661,79 @@ impl FieldSpecifier {
 }
 
 /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.4.3>
-#[derive(PartialEq, Clone, Debug)]
+#[derive(PartialEq, Clone, Debug, Default)]
 pub struct DataRecord {
-    pub values: HashMap<DataRecordKey, DataRecordValue>,
+    /// values in template order, including repeated Information Elements
+    pub values: Vec<(DataRecordKey, FieldValue)>,
+    /// number of leading `values` that are scope fields, for records
+    /// described by an Options Template
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.4.2.2>
+    pub scope_field_count: u16,
+}
+
+impl DataRecord {
+    /// Scope values, for records described by an Options Template
+    pub fn scope_values(&self) -> &[(DataRecordKey, FieldValue)] {
+        &self.values[..self.scope_len()]
+    }
+
+    /// Non-scope values. For records not described by an Options
+    /// Template, these are all of the values
+    pub fn option_values(&self) -> &[(DataRecordKey, FieldValue)] {
+        &self.values[self.scope_len()..]
+    }
+
+    fn scope_len(&self) -> usize {
+        usize::from(self.scope_field_count).min(self.values.len())
+    }
+
+    /// Get the first value for `key`
+    pub fn get(&self, key: &DataRecordKey) -> Option<&DataRecordValue> {
+        self.values
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| &v.value)
+    }
+
+    /// Get all values for `key`, in template order
+    pub fn get_all<'a>(
+        &'a self,
+        key: &'a DataRecordKey,
+    ) -> impl Iterator<Item = &'a DataRecordValue> + 'a {
+        self.values
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| &v.value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&DataRecordKey, &DataRecordValue)> {
+        self.values.iter().map(|(k, v)| (k, &v.value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Whether any value could not be decoded as its type, see
+    /// `ParseOptions::raw_fallback`
+    pub fn has_raw_values(&self) -> bool {
+        self.values
+            .iter()
+            .any(|(_, v)| matches!(v.value, DataRecordValue::Raw { .. }))
+    }
+}
+
+impl FromIterator<(DataRecordKey, DataRecordValue)> for DataRecord {
+    fn from_iter<T: IntoIterator<Item = (DataRecordKey, DataRecordValue)>>(iter: T) -> Self {
+        Self {
+            values: iter.into_iter().map(|(k, v)| (k, v.into())).collect(),
+            scope_field_count: 0,
+        }
+    }
 }
 
 /// slightly nicer syntax to make a `DataRecord`
@@ -231,197 +741,712 @@ pub struct DataRecord {
 macro_rules! data_record {
     { $($key:literal: $type:ident($value:expr)),+ $(,)? } => {
         DataRecord {
-            values: HashMap::from_iter([
-                $( ((DataRecordKey::Str($key), DataRecordValue::$type($value))), )+
-            ])
+            values: vec![
+                $( (DataRecordKey::Str($key), DataRecordValue::$type($value).into()), )+
+            ],
+            scope_field_count: 0,
         }
     };
 }
 
+impl DataRecord {
+    pub(crate) fn read_fields<R: Read + Seek>(
+        reader: &mut R,
+        endian: Endian,
+        template_id: u16,
+        template: &Template,
+        ctx: FieldContext,
+    ) -> BinResult<Self> {
+        let field_specifiers = template.field_specifiers();
+        let mut values = Vec::with_capacity(field_specifiers.len());
+        for (field_index, field_spec) in field_specifiers.iter().enumerate() {
+            let pos = reader.stream_position()?;
+            let long_prefix =
+                field_spec.field_length == u16::MAX && peek_long_prefix(reader, endian)?;
+            // TODO: should read whole field length according to template, regardless of type
+            let value = DataRecordValue::read_field(
+                reader,
+                endian,
+                field_spec.ty,
+                field_spec.field_length,
+                ctx,
+            )
+            .map_err(|e| field_error(e, pos, template_id, field_index, field_spec))?;
+
+            values.push((field_spec.name.clone(), FieldValue { value, long_prefix }));
+        }
+        Ok(Self {
+            values,
+            scope_field_count: template.scope_field_count(),
+        })
+    }
+
+    /// Read the records of a data set from its content, stopping at any
+    /// trailing padding, which is returned with them
+    pub(crate) fn read_data_set(
+        content: &[u8],
+        template_id: u16,
+        template: &Template,
+        ctx: FieldContext,
+    ) -> BinResult<(Vec<Self>, Vec<u8>)> {
+        let mut reader = Cursor::new(content);
+        let records = Self::read_set_records(
+            &mut reader,
+            Endian::Big,
+            template_id,
+            template,
+            content.len() as u64,
+            ctx,
+        )?;
+        let end = reader.position();
+        let padding = &content[end as usize..];
+        check_padding(padding, end, ctx.options.strict_padding)?;
+        Ok((records, padding.to_vec()))
+    }
+
+    /// Read the records of a data set until `end`. Only fewer bytes than
+    /// the template's `min_record_length` after the last record are
+    /// padding, so the records of a template of only variable length
+    /// fields, which may be as short as 1 byte, should not be padded
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.3.1>
+    fn read_set_records<R: Read + Seek>(
+        reader: &mut R,
+        endian: Endian,
+        template_id: u16,
+        template: &Template,
+        end: u64,
+        ctx: FieldContext,
+    ) -> BinResult<Vec<Self>> {
+        let limits = ctx.options.limits;
+        let min_length = template.min_record_length() as u64;
+        let mut records = Vec::new();
+        let mut bytes = 0;
+        // a template without fields would never make progress
+        if template.field_specifiers().is_empty() {
+            return Ok(records);
+        }
+        loop {
+            let start = reader.stream_position()?;
+            if start >= end || end - start < min_length {
+                return Ok(records);
+            }
+            let record = match Self::read_fields(reader, endian, template_id, template, ctx) {
+                Ok(record) => record,
+                Err(e) if e.is_eof() => {
+                    return Err(IpfixError::TruncatedRecord.into_binrw_error(start));
+                }
+                Err(e) => return Err(e),
+            };
+            bytes += record.allocated_bytes();
+            let exceeded = if records.len() == limits.max_records {
+                Some((Limit::Records, limits.max_records))
+            } else if bytes > limits.max_bytes {
+                Some((Limit::Bytes, limits.max_bytes))
+            } else {
+                None
+            };
+            if let Some((limit, max)) = exceeded {
+                return Err(IpfixError::LimitExceeded { limit, max }.into_binrw_error(start));
+            }
+            records.push(record);
+        }
+    }
+
+    /// Read records until `end`, e.g. the end of a sub template list
+    fn read_until<R: Read + Seek>(
+        reader: &mut R,
+        endian: Endian,
+        template_id: u16,
+        template: &Template,
+        end: u64,
+        ctx: FieldContext,
+    ) -> BinResult<Vec<Self>> {
+        let mut records = Vec::new();
+        // a template without fields would never make progress
+        if template.field_specifiers().is_empty() {
+            return Ok(records);
+        }
+        loop {
+            let start = reader.stream_position()?;
+            if start >= end {
+                return Ok(records);
+            }
+            records.push(Self::read_fields(
+                reader,
+                endian,
+                template_id,
+                template,
+                ctx,
+            )?);
+            ctx.check_list_item(reader, start, records.len())?;
+        }
+    }
+
+    /// Match each field spec to a value, converted to the field's type and
+    /// length (see `DataRecordValue::coerce`) and checked against the
+    /// template. Errors are at `pos`, the start of the record, as nothing
+    /// has been written yet
+    fn check_fields(
+        &self,
+        pos: u64,
+        template_id: u16,
+        field_specifiers: &[ExpandedFieldSpecifier],
+        validation: Validation,
+    ) -> BinResult<Vec<(usize, Cow<'_, DataRecordValue>)>> {
+        // values may be in any order (e.g. built by hand), so match each
+        // field spec to the next unused value with the same key
+        let mut used = vec![false; self.values.len()];
+        let mut values = Vec::with_capacity(field_specifiers.len());
+        for (i, field_spec) in field_specifiers.iter().enumerate() {
+            let error = |kind: IpfixError| {
+                field_error(kind.into_binrw_error(pos), pos, template_id, i, field_spec)
+            };
+            // fast path: records read from a template are already in template order
+            let index = match self.values.get(i) {
+                Some((key, _)) if !used[i] && *key == field_spec.name => Some(i),
+                _ => self
+                    .values
+                    .iter()
+                    .zip(&used)
+                    .position(|((key, _), used)| !used && *key == field_spec.name),
+            }
+            .ok_or_else(|| error(IpfixError::MissingData(field_spec.name.clone())))?;
+            used[index] = true;
+
+            let value = self.values[index]
+                .1
+                .value
+                .coerce(Some(field_spec.ty), field_spec.field_length)
+                .map_err(error)?;
+            value
+                .check(Some(field_spec.ty), field_spec.field_length, validation)
+                .map_err(error)?;
+            values.push((index, value));
+        }
+
+        if validation == Validation::Strict {
+            if let Some(index) = used.iter().position(|used| !used) {
+                let key = self.values[index].0.clone();
+                return Err(IpfixError::UnusedData(key).into_binrw_error(pos));
+            }
+        }
+        Ok(values)
+    }
+
+    fn write_fields<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        endian: Endian,
+        template_id: u16,
+        field_specifiers: &[ExpandedFieldSpecifier],
+        ctx: FieldContext,
+    ) -> BinResult<()> {
+        let values = self.check_fields(
+            writer.stream_position()?,
+            template_id,
+            field_specifiers,
+            ctx.validation,
+        )?;
+        for (i, (field_spec, (index, value))) in field_specifiers.iter().zip(values).enumerate() {
+            let pos = writer.stream_position()?;
+            value
+                .write_field(
+                    writer,
+                    endian,
+                    field_spec.field_length,
+                    self.values[index].1.long_prefix,
+                    ctx,
+                )
+                .map_err(|e| field_error(e, pos, template_id, i, field_spec))?;
+        }
+        Ok(())
+    }
+}
+
+impl Allocation for DataRecord {
+    fn allocated_bytes(&self) -> usize {
+        let values: usize = self
+            .values
+            .iter()
+            .map(|(_, value)| size_of::<DataRecordKey>() + value.allocated_bytes())
+            .sum();
+        size_of::<Self>() + values
+    }
+}
+
 impl BinRead for DataRecord {
-    type Args<'a> = (u16, TemplateStore);
+    type Args<'a> = (u16, TemplateStore, Rc<Formatter>, ParseOptions);
+
+    fn read_options<R: Read + Seek>(
+        reader: &mut R,
+        endian: Endian,
+        (set_id, templates, formatter, options): Self::Args<'_>,
+    ) -> BinResult<Self> {
+        let ctx = FieldContext {
+            templates: Some(&*templates),
+            formatter: Some(&formatter),
+            options,
+            ..Default::default()
+        };
+        let pos = reader.stream_position()?;
+        let template = ctx.get_template(set_id, pos)?;
+
+        // rewind a partial record, so a set's padding starts where its
+        // last record ends
+        Self::read_fields(reader, endian, set_id, &template, ctx).or_else(|e| {
+            reader.seek(SeekFrom::Start(pos))?;
+            Err(e)
+        })
+    }
+}
+
+impl BinWrite for DataRecord {
+    type Args<'a> = (u16, TemplateStore, Validation);
+
+    fn write_options<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        endian: Endian,
+        (set_id, templates, validation): Self::Args<'_>,
+    ) -> BinResult<()> {
+        let ctx = FieldContext {
+            templates: Some(&*templates),
+            validation,
+            ..Default::default()
+        };
+        let template = ctx.get_template(set_id, writer.stream_position()?)?;
+
+        self.write_fields(writer, endian, set_id, template.field_specifiers(), ctx)
+    }
+}
+
+/// Add the field to an error from reading or writing it. End of file
+/// errors are left as they are, as they mark the end of a set
+fn field_error(
+    error: binrw::Error,
+    pos: u64,
+    template_id: u16,
+    field_index: usize,
+    field_spec: &ExpandedFieldSpecifier,
+) -> binrw::Error {
+    if error.is_eof() {
+        return error;
+    }
+    FieldError {
+        pos,
+        template_id,
+        field_index,
+        information_element: (
+            field_spec.enterprise_number.unwrap_or(0),
+            field_spec.information_element_identifier,
+        ),
+        error,
+    }
+    .into_binrw_error()
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub enum DataRecordKey {
+    Str(&'static str),
+    Unrecognized(FieldSpecifier),
+    Err(String),
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum DataRecordType {
+    UnsignedInt,
+    SignedInt,
+    Float,
+    Bool,
+    MacAddress,
+    Bytes,
+    String,
+    DateTimeSeconds,
+    DateTimeMilliseconds,
+    DateTimeMicroseconds,
+    DateTimeNanoseconds,
+    Ipv4Addr,
+    Ipv6Addr,
+    BasicList,
+    SubTemplateList,
+    SubTemplateMultiList,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum DataRecordValue {
+    U8(u8),
+    U16(u16),
+    U24(u32),
+    U32(u32),
+    U40(u64),
+    U48(u64),
+    U56(u64),
+    U64(u64),
+    I8(i8),
+    I16(i16),
+    I24(i32),
+    I32(i32),
+    I40(i64),
+    I48(i64),
+    I56(i64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+
+    MacAddress([u8; 6]),
+
+    Bytes(Vec<u8>),
+    String(String),
+
+    DateTimeSeconds(u32),
+    DateTimeMilliseconds(u64),
+    DateTimeMicroseconds(NtpTimestamp),
+    DateTimeNanoseconds(NtpTimestamp),
+
+    Ipv4Addr(Ipv4Addr),
+    Ipv6Addr(Ipv6Addr),
+
+    BasicList(BasicList),
+    SubTemplateList(SubTemplateList),
+    SubTemplateMultiList(SubTemplateMultiList),
+
+    /// A field whose length does not fit its type, kept as it was read
+    /// (see `ParseOptions::raw_fallback`)
+    Raw {
+        ty: DataRecordType,
+        bytes: Vec<u8>,
+    },
+}
+
+/// A value of a `DataRecord` or `BasicList`, with how its length was
+/// encoded, so that it is written back the same way. That is not part of
+/// the value, so is ignored when comparing
+#[derive(Clone, Debug)]
+pub struct FieldValue {
+    pub value: DataRecordValue,
+    /// the variable length was encoded with the 3 byte prefix, although it
+    /// is less than 255
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-7>
+    pub long_prefix: bool,
+}
+
+impl PartialEq for FieldValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl From<DataRecordValue> for FieldValue {
+    fn from(value: DataRecordValue) -> Self {
+        Self {
+            value,
+            long_prefix: false,
+        }
+    }
+}
+
+/// <https://www.rfc-editor.org/rfc/rfc6313#section-4.4>
+#[binrw]
+#[brw(big)]
+#[br(map = |x: u8| Self::from(x))]
+#[bw(map = |x: &Self| u8::from(*x))]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum ListSemantic {
+    NoneOf,
+    ExactlyOneOf,
+    OneOrMoreOf,
+    AllOf,
+    Ordered,
+    Undefined,
+    /// A semantic not registered with IANA, kept so that it is written
+    /// back unchanged
+    Unknown(u8),
+}
+
+impl From<u8> for ListSemantic {
+    fn from(value: u8) -> Self {
+        match value {
+            0x00 => Self::NoneOf,
+            0x01 => Self::ExactlyOneOf,
+            0x02 => Self::OneOrMoreOf,
+            0x03 => Self::AllOf,
+            0x04 => Self::Ordered,
+            0xFF => Self::Undefined,
+            x => Self::Unknown(x),
+        }
+    }
+}
+
+impl From<ListSemantic> for u8 {
+    fn from(semantic: ListSemantic) -> Self {
+        match semantic {
+            ListSemantic::NoneOf => 0x00,
+            ListSemantic::ExactlyOneOf => 0x01,
+            ListSemantic::OneOrMoreOf => 0x02,
+            ListSemantic::AllOf => 0x03,
+            ListSemantic::Ordered => 0x04,
+            ListSemantic::Undefined => 0xFF,
+            ListSemantic::Unknown(x) => x,
+        }
+    }
+}
+
+/// <https://www.rfc-editor.org/rfc/rfc6313#section-4.5.3>
+#[derive(PartialEq, Clone, Debug)]
+pub struct BasicList {
+    pub semantic: ListSemantic,
+    /// Information Element and length of each element in the list
+    pub field_specifier: FieldSpecifier,
+    pub values: Vec<FieldValue>,
+}
+
+/// <https://www.rfc-editor.org/rfc/rfc6313#section-4.5.4>
+#[derive(PartialEq, Clone, Debug)]
+pub struct SubTemplateList {
+    pub semantic: ListSemantic,
+    pub template_id: u16,
+    pub data: Vec<DataRecord>,
+}
+
+/// <https://www.rfc-editor.org/rfc/rfc6313#section-4.5.5>
+#[derive(PartialEq, Clone, Debug)]
+pub struct SubTemplateMultiList {
+    pub semantic: ListSemantic,
+    pub entries: Vec<SubTemplateMultiListEntry>,
+}
+
+/// A group of records from the same template in a `SubTemplateMultiList`
+#[derive(PartialEq, Clone, Debug)]
+pub struct SubTemplateMultiListEntry {
+    pub template_id: u16,
+    pub data: Vec<DataRecord>,
+}
+
+/// Information needed to read or write a field: the options for the
+/// current parse or write, and for structured data [RFC6313], whose
+/// elements are described in the data rather than the template, the
+/// templates
+#[derive(Clone, Copy, Default)]
+pub(crate) struct FieldContext<'a> {
+    pub templates: Option<&'a dyn TemplateStorage>,
+    pub formatter: Option<&'a Formatter>,
+    pub options: ParseOptions,
+    pub validation: Validation,
+    /// structured data lists around the field being read
+    pub depth: usize,
+}
+
+impl FieldContext<'_> {
+    fn get_template(&self, template_id: u16, pos: u64) -> BinResult<Template> {
+        self.templates
+            .and_then(|templates| templates.get_template(template_id))
+            .ok_or_else(|| IpfixError::MissingTemplate(template_id).into_binrw_error(pos))
+    }
+
+    /// The context for the content of a structured data list at `pos`,
+    /// one level deeper
+    fn nested(&self, pos: u64) -> BinResult<Self> {
+        let max = self.options.limits.max_depth;
+        if self.depth >= max {
+            return Err(IpfixError::LimitExceeded {
+                limit: Limit::Depth,
+                max,
+            }
+            .into_binrw_error(pos));
+        }
+        Ok(Self {
+            depth: self.depth + 1,
+            ..*self
+        })
+    }
+
+    /// Check the `count`th record or element of a list, read from `start`.
+    /// Each must take up some bytes, or the list would never end
+    fn check_list_item<R: Seek>(&self, reader: &mut R, start: u64, count: usize) -> BinResult<()> {
+        let max = self.options.limits.max_records;
+        if count > max {
+            return Err(IpfixError::LimitExceeded {
+                limit: Limit::Records,
+                max,
+            }
+            .into_binrw_error(start));
+        }
+        if reader.stream_position()? == start {
+            return Err(IpfixError::EmptyListElement.into_binrw_error(start));
+        }
+        Ok(())
+    }
+}
 
-    fn read_options<R: Read + Seek>(
+impl BasicList {
+    fn read_content<R: Read + Seek>(
         reader: &mut R,
         endian: Endian,
-        (set_id, templates): Self::Args<'_>,
+        end: u64,
+        ctx: FieldContext,
     ) -> BinResult<Self> {
-        let template = templates.get_template(set_id).ok_or(
-            IpfixError::MissingTemplate(set_id).into_binrw_error(reader.stream_position()?),
-        )?;
-
-        // TODO: should these be handled differently?
-        let field_specifiers = match template {
-            Template::Template(field_specifiers) => field_specifiers,
-            Template::OptionsTemplate(field_specifiers) => field_specifiers,
+        let semantic = reader.read_type(endian)?;
+        let field_specifier: FieldSpecifier = reader.read_type(endian)?;
+        let ty = match ctx.formatter {
+            Some(formatter) => {
+                ExpandedFieldSpecifier::from_field_spec(&field_specifier, formatter).ty
+            }
+            None => DataRecordType::Bytes,
         };
 
-        let mut values = HashMap::with_capacity(field_specifiers.len());
-        for field_spec in field_specifiers.iter() {
-            // TODO: should read whole field length according to template, regardless of type
-            let value = reader.read_type_args(endian, (field_spec.ty, field_spec.field_length))?;
-
-            values.insert(field_spec.name.clone(), value);
+        let mut values = Vec::new();
+        loop {
+            let start = reader.stream_position()?;
+            if start >= end {
+                break;
+            }
+            let long_prefix =
+                field_specifier.field_length == u16::MAX && peek_long_prefix(reader, endian)?;
+            let value =
+                DataRecordValue::read_field(reader, endian, ty, field_specifier.field_length, ctx)?;
+            values.push(FieldValue { value, long_prefix });
+            ctx.check_list_item(reader, start, values.len())?;
         }
-        Ok(Self { values })
+        Ok(Self {
+            semantic,
+            field_specifier,
+            values,
+        })
     }
-}
-
-impl BinWrite for DataRecord {
-    type Args<'a> = (u16, TemplateStore);
 
-    fn write_options<W: Write + Seek>(
+    fn write_content<W: Write + Seek>(
         &self,
         writer: &mut W,
         endian: Endian,
-        (set_id, templates): Self::Args<'_>,
+        ctx: FieldContext,
     ) -> BinResult<()> {
-        let template = templates.get_template(set_id).ok_or(
-            IpfixError::MissingTemplate(set_id).into_binrw_error(writer.stream_position()?),
-        )?;
-
-        let field_specifiers = match template {
-            Template::Template(field_specifiers) => field_specifiers,
-            Template::OptionsTemplate(field_specifiers) => field_specifiers,
-        };
-
-        // TODO: should check if all keys are used?
-        for field_spec in field_specifiers {
-            // TODO: check template type vs actual type?
-            let value = self.values.get(&field_spec.name).ok_or(
-                IpfixError::MissingData(field_spec.name)
-                    .into_binrw_error(writer.stream_position()?),
+        writer.write_type(&self.semantic, endian)?;
+        writer.write_type(&self.field_specifier, endian)?;
+        for FieldValue { value, long_prefix } in &self.values {
+            // element types are only known with a formatter, so only
+            // their lengths are checked
+            let pos = writer.stream_position()?;
+            let value = value
+                .coerce(None, self.field_specifier.field_length)
+                .and_then(|value| {
+                    value.check(None, self.field_specifier.field_length, ctx.validation)?;
+                    Ok(value)
+                })
+                .map_err(|e| e.into_binrw_error(pos))?;
+            value.write_field(
+                writer,
+                endian,
+                self.field_specifier.field_length,
+                *long_prefix,
+                ctx,
             )?;
-
-            writer.write_type_args(value, endian, (field_spec.field_length,))?;
         }
         Ok(())
     }
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
-pub enum DataRecordKey {
-    Str(&'static str),
-    Unrecognized(FieldSpecifier),
-    Err(String),
-}
-
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
-pub enum DataRecordType {
-    UnsignedInt,
-    SignedInt,
-    Float,
-    Bool,
-    MacAddress,
-    Bytes,
-    String,
-    DateTimeSeconds,
-    DateTimeMilliseconds,
-    DateTimeMicroseconds,
-    DateTimeNanoseconds,
-    Ipv4Addr,
-    Ipv6Addr,
-}
-
-#[repr(C)]
-#[derive(Debug, PartialEq, Clone)]
-struct U40Bytes([u8; 5]);
-
-impl BinWrite for U40Bytes {
-    type Args<'a> = ();
+impl SubTemplateList {
+    fn read_content<R: Read + Seek>(
+        reader: &mut R,
+        endian: Endian,
+        end: u64,
+        ctx: FieldContext,
+    ) -> BinResult<Self> {
+        let semantic = reader.read_type(endian)?;
+        let template_id = reader.read_type(endian)?;
+        let template = ctx.get_template(template_id, reader.stream_position()?)?;
+        let data = DataRecord::read_until(reader, endian, template_id, &template, end, ctx)?;
+        Ok(Self {
+            semantic,
+            template_id,
+            data,
+        })
+    }
 
-    fn write_options<W: Write + Seek>(
+    fn write_content<W: Write + Seek>(
         &self,
         writer: &mut W,
-        _: Endian,
-        _: Self::Args<'_>,
+        endian: Endian,
+        ctx: FieldContext,
     ) -> BinResult<()> {
-        let start_pos = writer.stream_position()?;
-        writer.write_all(&self.0)?;
-        let end_pos = writer.stream_position()?;
-        assert_eq!(
-            end_pos - start_pos,
-            5,
-            "U40Bytes wrote wrong number of bytes"
-        );
+        writer.write_type(&self.semantic, endian)?;
+        writer.write_type(&self.template_id, endian)?;
+        let template = ctx.get_template(self.template_id, writer.stream_position()?)?;
+        for record in &self.data {
+            record.write_fields(
+                writer,
+                endian,
+                self.template_id,
+                template.field_specifiers(),
+                ctx,
+            )?;
+        }
         Ok(())
     }
 }
 
-impl BinRead for U40Bytes {
-    type Args<'a> = ();
-
-    fn read_options<R: Read + Seek>(
+impl SubTemplateMultiList {
+    fn read_content<R: Read + Seek>(
         reader: &mut R,
-        _: Endian,
-        _: Self::Args<'_>,
+        endian: Endian,
+        end: u64,
+        ctx: FieldContext,
     ) -> BinResult<Self> {
-        let mut bytes = [0u8; 5];
-        reader.read_exact(&mut bytes)?;
-        Ok(U40Bytes(bytes))
+        let semantic = reader.read_type(endian)?;
+        let mut entries = Vec::new();
+        while reader.stream_position()? < end {
+            let template_id = reader.read_type(endian)?;
+            let length: u16 = reader.read_type(endian)?;
+            let pos = reader.stream_position()?;
+            if length < 4 {
+                return Err(binrw::Error::AssertFail {
+                    pos,
+                    message: format!("invalid sub template multi list length: [{length} < 4]"),
+                });
+            }
+            let template = ctx.get_template(template_id, pos)?;
+            let data = DataRecord::read_until(
+                reader,
+                endian,
+                template_id,
+                &template,
+                pos + u64::from(length - 4),
+                ctx,
+            )?;
+            entries.push(SubTemplateMultiListEntry { template_id, data });
+        }
+        Ok(Self { semantic, entries })
     }
-}
-
-#[binwrite]
-#[bw(big)]
-#[bw(import( length: u16 ))]
-#[derive(PartialEq, Clone, Debug)]
-pub enum DataRecordValue {
-    U8(u8),
-    U16(u16),
-    U32(u32),
-    U40(
-        #[bw(try_map = |x: &u64| -> BinResult<U40Bytes> {
-        if *x > 0xFF_FFFF_FFFF {
-            return Err(binrw::Error::Custom {
-                pos: 0,
-                err: Box::new("Value too large for U40"),
-            });
-        }
-        Ok(U40Bytes([
-            ((*x >> 32) & 0xFF) as u8,
-            ((*x >> 24) & 0xFF) as u8,
-            ((*x >> 16) & 0xFF) as u8,
-            ((*x >> 8) & 0xFF) as u8,
-            (*x & 0xFF) as u8,
-        ]))
-    })]
-        u64,
-    ),
-    U64(u64),
-    I8(i8),
-    I16(i16),
-    I32(i32),
-    I64(i64),
-    F32(f32),
-    F64(f64),
-    Bool(#[bw(map = |&x| -> u8 {if x {1} else {2} })] bool),
-
-    MacAddress([u8; 6]),
-
-    // TODO: same logic as variable length string
-    Bytes(
-        #[bw(if(length == u16::MAX), calc = if self_2.len() < 255 { self_2.len() as u8 } else { 255 })]
-         u8,
-        #[bw(if(length == u16::MAX && self_2.len() >= 255), try_calc = self_2.len().try_into())]
-        u16,
-        Vec<u8>,
-    ),
-    String(
-        #[bw(if(length == u16::MAX), calc = if self_2.len() < 255 { self_2.len() as u8 } else { 255 })]
-         u8,
-        #[bw(if(length == u16::MAX && self_2.len() >= 255), try_calc = self_2.len().try_into())]
-        u16,
-        #[bw(map = |x| x.as_bytes())] String,
-    ),
-
-    DateTimeSeconds(u32),
-    DateTimeMilliseconds(u64),
-    DateTimeMicroseconds(u64),
-    DateTimeNanoseconds(u64),
 
-    Ipv4Addr(#[bw(map = |&x| -> u32 {x.into()})] Ipv4Addr),
-    Ipv6Addr(#[bw(map = |&x| -> u128 {x.into()})] Ipv6Addr),
+    fn write_content<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        endian: Endian,
+        ctx: FieldContext,
+    ) -> BinResult<()> {
+        writer.write_type(&self.semantic, endian)?;
+        for entry in &self.entries {
+            let template = ctx.get_template(entry.template_id, writer.stream_position()?)?;
+            let mut content = Cursor::new(Vec::new());
+            for record in &entry.data {
+                record.write_fields(
+                    &mut content,
+                    endian,
+                    entry.template_id,
+                    template.field_specifiers(),
+                    ctx,
+                )?;
+            }
+            let length = u16::try_from(content.get_ref().len() + 4).map_err(|_| {
+                IpfixError::LengthOverflow(content.get_ref().len() + 4).into_binrw_error(0)
+            })?;
+            writer.write_type(&entry.template_id, endian)?;
+            writer.write_type(&length, endian)?;
+            writer.write_all(content.get_ref())?;
+        }
+        Ok(())
+    }
 }
 
 fn read_variable_length<R: Read + Seek>(
@@ -429,18 +1454,111 @@ fn read_variable_length<R: Read + Seek>(
     endian: Endian,
     length: u16,
 ) -> BinResult<Vec<u8>> {
-    let actual_length = if length == u16::MAX {
+    let actual_length = read_length(reader, endian, length)?;
+    count(actual_length.into())(reader, endian, ())
+}
+
+/// Read the length prefix of a variable length field, or return the
+/// field's length if it is fixed
+fn read_length<R: Read + Seek>(reader: &mut R, endian: Endian, length: u16) -> BinResult<u16> {
+    Ok(if length == u16::MAX {
         let var_length: u8 = reader.read_type(endian)?;
         if var_length == 255 {
-            let var_length_ext: u16 = reader.read_type(endian)?;
-            var_length_ext
+            reader.read_type(endian)?
         } else {
             var_length.into()
         }
     } else {
         length
-    };
-    count(actual_length.into())(reader, endian, ())
+    })
+}
+
+/// Read the content of a structured data list field where it is, rather
+/// than from a copy, as a copy at each level of nested lists would hold
+/// the rest of the field again
+fn read_list_content<R: Read + Seek, T>(
+    reader: &mut R,
+    endian: Endian,
+    length: u16,
+    ctx: FieldContext,
+    read_content: fn(&mut R, Endian, u64, FieldContext) -> BinResult<T>,
+) -> BinResult<T> {
+    let ctx = ctx.nested(reader.stream_position()?)?;
+    let length = read_length(reader, endian, length)?;
+    let start = reader.stream_position()?;
+    let end = start + u64::from(length);
+    let content = read_content(reader, endian, end, ctx)?;
+    // the last element may run past the end of the list, into whatever
+    // follows it
+    if reader.stream_position()? > end {
+        return Err(IpfixError::TruncatedRecord.into_binrw_error(start));
+    }
+    Ok(content)
+}
+
+/// Whether the variable length prefix at the reader uses the 3 byte form
+/// for a length that would fit in 1 byte. The reader is left where it was
+fn peek_long_prefix<R: Read + Seek>(reader: &mut R, endian: Endian) -> BinResult<bool> {
+    let pos = reader.stream_position()?;
+    let var_length: u8 = reader.read_type(endian)?;
+    let long = var_length == 255 && reader.read_type::<u16>(endian)? < 255;
+    reader.seek(SeekFrom::Start(pos))?;
+    Ok(long)
+}
+
+/// Write `bytes`, prefixed by their length if `length` indicates a
+/// variable length field. `long_prefix` uses the 3 byte prefix even for
+/// lengths that fit in 1 byte
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-7>
+fn write_variable_length<W: Write + Seek>(
+    writer: &mut W,
+    endian: Endian,
+    length: u16,
+    long_prefix: bool,
+    bytes: &[u8],
+) -> BinResult<()> {
+    if length == u16::MAX {
+        if bytes.len() < 255 && !long_prefix {
+            writer.write_type(&(bytes.len() as u8), endian)?;
+        } else {
+            let var_length_ext = u16::try_from(bytes.len())
+                .map_err(|_| IpfixError::LengthOverflow(bytes.len()).into_binrw_error(0))?;
+            writer.write_type(&255u8, endian)?;
+            writer.write_type(&var_length_ext, endian)?;
+        }
+    }
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+impl Allocation for FieldValue {
+    fn allocated_bytes(&self) -> usize {
+        size_of::<Self>() - size_of::<DataRecordValue>() + self.value.allocated_bytes()
+    }
+}
+
+impl Allocation for DataRecordValue {
+    fn allocated_bytes(&self) -> usize {
+        let records =
+            |data: &[DataRecord]| -> usize { data.iter().map(|r| r.allocated_bytes()).sum() };
+        size_of::<Self>()
+            + match self {
+                Self::Bytes(bytes) | Self::Raw { bytes, .. } => bytes.len(),
+                Self::String(string) => string.len(),
+                Self::BasicList(list) => list
+                    .values
+                    .iter()
+                    .map(|v| v.allocated_bytes())
+                    .sum::<usize>(),
+                Self::SubTemplateList(list) => records(&list.data),
+                Self::SubTemplateMultiList(list) => list
+                    .entries
+                    .iter()
+                    .map(|entry| size_of::<SubTemplateMultiListEntry>() + records(&entry.data))
+                    .sum(),
+                _ => 0,
+            }
+    }
 }
 
 impl BinRead for DataRecordValue {
@@ -450,17 +1568,375 @@ impl BinRead for DataRecordValue {
         reader: &mut R,
         endian: Endian,
         (ty, length): Self::Args<'_>,
+    ) -> BinResult<Self> {
+        Self::read_field(reader, endian, ty, length, FieldContext::default())
+    }
+}
+
+impl BinWrite for DataRecordValue {
+    type Args<'a> = (u16,);
+
+    fn write_options<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        endian: Endian,
+        (length,): Self::Args<'_>,
+    ) -> BinResult<()> {
+        self.write_field(writer, endian, length, false, FieldContext::default())
+    }
+}
+
+impl DataRecordValue {
+    /// The abstract data type of the value
+    pub fn data_type(&self) -> DataRecordType {
+        match self {
+            Self::U8(_)
+            | Self::U16(_)
+            | Self::U24(_)
+            | Self::U32(_)
+            | Self::U40(_)
+            | Self::U48(_)
+            | Self::U56(_)
+            | Self::U64(_) => DataRecordType::UnsignedInt,
+            Self::I8(_)
+            | Self::I16(_)
+            | Self::I24(_)
+            | Self::I32(_)
+            | Self::I40(_)
+            | Self::I48(_)
+            | Self::I56(_)
+            | Self::I64(_) => DataRecordType::SignedInt,
+            Self::F32(_) | Self::F64(_) => DataRecordType::Float,
+            Self::Bool(_) => DataRecordType::Bool,
+            Self::MacAddress(_) => DataRecordType::MacAddress,
+            Self::Bytes(_) => DataRecordType::Bytes,
+            Self::String(_) => DataRecordType::String,
+            Self::DateTimeSeconds(_) => DataRecordType::DateTimeSeconds,
+            Self::DateTimeMilliseconds(_) => DataRecordType::DateTimeMilliseconds,
+            Self::DateTimeMicroseconds(_) => DataRecordType::DateTimeMicroseconds,
+            Self::DateTimeNanoseconds(_) => DataRecordType::DateTimeNanoseconds,
+            Self::Ipv4Addr(_) => DataRecordType::Ipv4Addr,
+            Self::Ipv6Addr(_) => DataRecordType::Ipv6Addr,
+            Self::BasicList(_) => DataRecordType::BasicList,
+            Self::SubTemplateList(_) => DataRecordType::SubTemplateList,
+            Self::SubTemplateMultiList(_) => DataRecordType::SubTemplateMultiList,
+            Self::Raw { ty, .. } => *ty,
+        }
+    }
+
+    /// The value of integer variants, whatever their width
+    pub fn as_i128(&self) -> Option<i128> {
+        Some(match self {
+            Self::U8(x) => (*x).into(),
+            Self::U16(x) => (*x).into(),
+            Self::U24(x) | Self::U32(x) => (*x).into(),
+            Self::U40(x) | Self::U48(x) | Self::U56(x) | Self::U64(x) => (*x).into(),
+            Self::I8(x) => (*x).into(),
+            Self::I16(x) => (*x).into(),
+            Self::I24(x) | Self::I32(x) => (*x).into(),
+            Self::I40(x) | Self::I48(x) | Self::I56(x) | Self::I64(x) => (*x).into(),
+            _ => return None,
+        })
+    }
+
+    /// Convert the value to the variant for a field of type `ty` (if
+    /// known, otherwise the value's own type) and `length`, e.g. a `U64`
+    /// to a `U32` for reduced-size encoding
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-6.2>, or
+    /// `DateTimeSeconds` to `DateTimeMilliseconds`. Values that cannot be
+    /// converted, or only by losing precision, e.g. milliseconds to
+    /// seconds, are returned as they are, for `check` to report
+    pub(crate) fn coerce(
+        &self,
+        ty: Option<DataRecordType>,
+        length: u16,
+    ) -> Result<Cow<'_, Self>, IpfixError> {
+        let ty = ty.unwrap_or_else(|| self.data_type());
+        let out_of_range = |value: i128| IpfixError::ValueOutOfRange { value, length };
+        let coerced = match (ty, length, self) {
+            (_, _, x) if x.data_type() == ty && x.fixed_length() == Some(length.into()) => {
+                return Ok(Cow::Borrowed(self))
+            }
+            (DataRecordType::UnsignedInt | DataRecordType::SignedInt, 1..=8, x)
+                if x.as_i128().is_some() =>
+            {
+                let value = x.as_i128().unwrap_or_default();
+                Self::integer(ty, length, value).ok_or_else(|| out_of_range(value))?
+            }
+            (DataRecordType::Float, 4, Self::F64(x)) => {
+                let narrowed = *x as f32;
+                if x.is_finite() && narrowed.is_infinite() {
+                    return Err(IpfixError::FloatOutOfRange { value: *x, length });
+                }
+                Self::F32(narrowed)
+            }
+            (DataRecordType::Float, 8, Self::F32(x)) => Self::F64((*x).into()),
+            (DataRecordType::DateTimeSeconds, 4, Self::DateTimeMilliseconds(x))
+                if x % 1000 == 0 =>
+            {
+                Self::DateTimeSeconds(
+                    u32::try_from(x / 1000).map_err(|_| out_of_range((*x).into()))?,
+                )
+            }
+            (DataRecordType::DateTimeMilliseconds, 8, Self::DateTimeSeconds(x)) => {
+                Self::DateTimeMilliseconds(u64::from(*x) * 1000)
+            }
+            (
+                DataRecordType::DateTimeMicroseconds | DataRecordType::DateTimeNanoseconds,
+                8,
+                Self::DateTimeSeconds(x),
+            ) => Self::ntp_from_millis(ty, u64::from(*x) * 1000)
+                .ok_or_else(|| out_of_range((*x).into()))?,
+            (
+                DataRecordType::DateTimeMicroseconds | DataRecordType::DateTimeNanoseconds,
+                8,
+                Self::DateTimeMilliseconds(x),
+            ) => Self::ntp_from_millis(ty, *x).ok_or_else(|| out_of_range((*x).into()))?,
+            // both are NTP timestamps, differing only in their precision
+            (DataRecordType::DateTimeMicroseconds, 8, Self::DateTimeNanoseconds(x)) => {
+                Self::DateTimeMicroseconds(*x)
+            }
+            (DataRecordType::DateTimeNanoseconds, 8, Self::DateTimeMicroseconds(x)) => {
+                Self::DateTimeNanoseconds(*x)
+            }
+            _ => return Ok(Cow::Borrowed(self)),
+        };
+        Ok(Cow::Owned(coerced))
+    }
+
+    /// The NTP timestamp variant for `ty` of `millis` since the Unix
+    /// epoch, if it can be represented
+    fn ntp_from_millis(ty: DataRecordType, millis: u64) -> Option<Self> {
+        let millis = i64::try_from(millis).ok()?;
+        if ty == DataRecordType::DateTimeMicroseconds {
+            NtpTimestamp::from_unix_micros(millis.checked_mul(1000)?)
+                .map(Self::DateTimeMicroseconds)
+        } else {
+            NtpTimestamp::from_unix_nanos(millis.checked_mul(1_000_000)?)
+                .map(Self::DateTimeNanoseconds)
+        }
+    }
+
+    /// The integer variant for `ty` and `length`, if `value` fits in it
+    fn integer(ty: DataRecordType, length: u16, value: i128) -> Option<Self> {
+        let bits = 8 * u32::from(length);
+        Some(if ty == DataRecordType::SignedInt {
+            let min = -(1i128 << (bits - 1));
+            if value < min || value > -min - 1 {
+                return None;
+            }
+            let x = value as i64;
+            match length {
+                1 => Self::I8(x as i8),
+                2 => Self::I16(x as i16),
+                3 => Self::I24(x as i32),
+                4 => Self::I32(x as i32),
+                5 => Self::I40(x),
+                6 => Self::I48(x),
+                7 => Self::I56(x),
+                _ => Self::I64(x),
+            }
+        } else {
+            if value < 0 || value >> bits != 0 {
+                return None;
+            }
+            let x = value as u64;
+            match length {
+                1 => Self::U8(x as u8),
+                2 => Self::U16(x as u16),
+                3 => Self::U24(x as u32),
+                4 => Self::U32(x as u32),
+                5 => Self::U40(x),
+                6 => Self::U48(x),
+                7 => Self::U56(x),
+                _ => Self::U64(x),
+            }
+        })
+    }
+
+    /// Encoded length of values that are always the same size
+    fn fixed_length(&self) -> Option<usize> {
+        Some(match self {
+            Self::U8(_) | Self::I8(_) | Self::Bool(_) => 1,
+            Self::U16(_) | Self::I16(_) => 2,
+            Self::U24(_) | Self::I24(_) => 3,
+            Self::U32(_) | Self::I32(_) | Self::F32(_) => 4,
+            Self::DateTimeSeconds(_) | Self::Ipv4Addr(_) => 4,
+            Self::U40(_) | Self::I40(_) => 5,
+            Self::U48(_) | Self::I48(_) | Self::MacAddress(_) => 6,
+            Self::U56(_) | Self::I56(_) => 7,
+            Self::U64(_) | Self::I64(_) | Self::F64(_) => 8,
+            Self::DateTimeMilliseconds(_)
+            | Self::DateTimeMicroseconds(_)
+            | Self::DateTimeNanoseconds(_) => 8,
+            Self::Ipv6Addr(_) => 16,
+            _ => return None,
+        })
+    }
+
+    /// Check that the value can be written to a field of type `ty` (if
+    /// known) and `length`
+    pub(crate) fn check(
+        &self,
+        ty: Option<DataRecordType>,
+        length: u16,
+        validation: Validation,
+    ) -> Result<(), IpfixError> {
+        match (ty, self) {
+            (Some(expected), _) if validation == Validation::Strict => {
+                let actual = self.data_type();
+                // strings that were not valid UTF-8 may be kept as bytes
+                let compatible = actual == expected
+                    || matches!(self, Self::Raw { .. })
+                    || (actual == DataRecordType::Bytes && expected == DataRecordType::String);
+                if !compatible {
+                    return Err(IpfixError::TypeMismatch { expected, actual });
+                }
+            }
+            _ => {}
+        }
+
+        match self {
+            Self::U24(x) => unsigned_in_range((*x).into(), 3)?,
+            Self::U40(x) => unsigned_in_range(*x, 5)?,
+            Self::U48(x) => unsigned_in_range(*x, 6)?,
+            Self::U56(x) => unsigned_in_range(*x, 7)?,
+            Self::I24(x) => signed_in_range((*x).into(), 3)?,
+            Self::I40(x) => signed_in_range(*x, 5)?,
+            Self::I48(x) => signed_in_range(*x, 6)?,
+            Self::I56(x) => signed_in_range(*x, 7)?,
+            _ => {}
+        }
+
+        let actual = match self {
+            // fixed length strings are padded with NULs
+            Self::String(x) if length != u16::MAX && x.len() <= usize::from(length) => {
+                return Ok(())
+            }
+            Self::String(x) => x.len(),
+            Self::Bytes(x) | Self::Raw { bytes: x, .. } => x.len(),
+            // the length of lists is only known once they are encoded
+            Self::BasicList(_) | Self::SubTemplateList(_) | Self::SubTemplateMultiList(_) => {
+                return Ok(())
+            }
+            _ => self.fixed_length().unwrap_or_default(),
+        };
+        let fits = match self.fixed_length() {
+            // fixed size values cannot be written with a length prefix
+            Some(_) => usize::from(length) == actual,
+            None => length == u16::MAX || usize::from(length) == actual,
+        };
+        if !fits {
+            return Err(IpfixError::FieldLengthMismatch { length, actual });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_field<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        endian: Endian,
+        length: u16,
+        long_prefix: bool,
+        ctx: FieldContext,
+    ) -> BinResult<()> {
+        match self {
+            Self::U8(x) => writer.write_type(x, endian),
+            Self::U16(x) => writer.write_type(x, endian),
+            Self::U24(x) => writer.write_type(&reduced_unsigned::<3>(x)?, endian),
+            Self::U32(x) => writer.write_type(x, endian),
+            Self::U40(x) => writer.write_type(&reduced_unsigned::<5>(x)?, endian),
+            Self::U48(x) => writer.write_type(&reduced_unsigned::<6>(x)?, endian),
+            Self::U56(x) => writer.write_type(&reduced_unsigned::<7>(x)?, endian),
+            Self::U64(x) => writer.write_type(x, endian),
+            Self::I8(x) => writer.write_type(x, endian),
+            Self::I16(x) => writer.write_type(x, endian),
+            Self::I24(x) => writer.write_type(&reduced_signed::<3>(x)?, endian),
+            Self::I32(x) => writer.write_type(x, endian),
+            Self::I40(x) => writer.write_type(&reduced_signed::<5>(x)?, endian),
+            Self::I48(x) => writer.write_type(&reduced_signed::<6>(x)?, endian),
+            Self::I56(x) => writer.write_type(&reduced_signed::<7>(x)?, endian),
+            Self::I64(x) => writer.write_type(x, endian),
+            Self::F32(x) => writer.write_type(x, endian),
+            Self::F64(x) => writer.write_type(x, endian),
+            Self::Bool(x) => writer.write_type(&if *x { 1u8 } else { 2u8 }, endian),
+            Self::MacAddress(x) => writer.write_type(x, endian),
+            Self::Bytes(x) => write_variable_length(writer, endian, length, long_prefix, x),
+            Self::String(x) => {
+                write_variable_length(writer, endian, length, long_prefix, x.as_bytes())?;
+                // fixed length strings may have had their NUL padding trimmed
+                if length != u16::MAX && x.len() < usize::from(length) {
+                    writer.write_all(&vec![0; usize::from(length) - x.len()])?;
+                }
+                Ok(())
+            }
+            Self::DateTimeSeconds(x) => writer.write_type(x, endian),
+            Self::DateTimeMilliseconds(x) => writer.write_type(x, endian),
+            Self::DateTimeMicroseconds(x) => writer.write_type(x, endian),
+            Self::DateTimeNanoseconds(x) => writer.write_type(x, endian),
+            Self::Ipv4Addr(x) => writer.write_type(&u32::from(*x), endian),
+            Self::Ipv6Addr(x) => writer.write_type(&u128::from(*x), endian),
+            Self::BasicList(list) => {
+                let mut content = Cursor::new(Vec::new());
+                list.write_content(&mut content, endian, ctx)?;
+                write_variable_length(writer, endian, length, long_prefix, content.get_ref())
+            }
+            Self::SubTemplateList(list) => {
+                let mut content = Cursor::new(Vec::new());
+                list.write_content(&mut content, endian, ctx)?;
+                write_variable_length(writer, endian, length, long_prefix, content.get_ref())
+            }
+            Self::SubTemplateMultiList(list) => {
+                let mut content = Cursor::new(Vec::new());
+                list.write_content(&mut content, endian, ctx)?;
+                write_variable_length(writer, endian, length, long_prefix, content.get_ref())
+            }
+            Self::Raw { bytes, .. } => {
+                write_variable_length(writer, endian, length, long_prefix, bytes)
+            }
+        }
+    }
+
+    pub(crate) fn read_field<R: Read + Seek>(
+        reader: &mut R,
+        endian: Endian,
+        ty: DataRecordType,
+        length: u16,
+        ctx: FieldContext,
     ) -> BinResult<Self> {
         // TODO: length shouldn't actually change the data type, technically
         Ok(match (ty, length) {
             (DataRecordType::UnsignedInt, 1) => DataRecordValue::U8(reader.read_type(endian)?),
             (DataRecordType::UnsignedInt, 2) => DataRecordValue::U16(reader.read_type(endian)?),
+            (DataRecordType::UnsignedInt, 3) => {
+                DataRecordValue::U24(read_reduced_unsigned::<_, 3>(reader)? as u32)
+            }
             (DataRecordType::UnsignedInt, 4) => DataRecordValue::U32(reader.read_type(endian)?),
-            (DataRecordType::UnsignedInt, 5) => DataRecordValue::U40(read_u40(reader)?),
+            (DataRecordType::UnsignedInt, 5) => {
+                DataRecordValue::U40(read_reduced_unsigned::<_, 5>(reader)?)
+            }
+            (DataRecordType::UnsignedInt, 6) => {
+                DataRecordValue::U48(read_reduced_unsigned::<_, 6>(reader)?)
+            }
+            (DataRecordType::UnsignedInt, 7) => {
+                DataRecordValue::U56(read_reduced_unsigned::<_, 7>(reader)?)
+            }
             (DataRecordType::UnsignedInt, 8) => DataRecordValue::U64(reader.read_type(endian)?),
             (DataRecordType::SignedInt, 1) => DataRecordValue::I8(reader.read_type(endian)?),
             (DataRecordType::SignedInt, 2) => DataRecordValue::I16(reader.read_type(endian)?),
+            (DataRecordType::SignedInt, 3) => {
+                DataRecordValue::I24(read_reduced_signed::<_, 3>(reader)? as i32)
+            }
             (DataRecordType::SignedInt, 4) => DataRecordValue::I32(reader.read_type(endian)?),
+            (DataRecordType::SignedInt, 5) => {
+                DataRecordValue::I40(read_reduced_signed::<_, 5>(reader)?)
+            }
+            (DataRecordType::SignedInt, 6) => {
+                DataRecordValue::I48(read_reduced_signed::<_, 6>(reader)?)
+            }
+            (DataRecordType::SignedInt, 7) => {
+                DataRecordValue::I56(read_reduced_signed::<_, 7>(reader)?)
+            }
             (DataRecordType::SignedInt, 8) => DataRecordValue::I64(reader.read_type(endian)?),
             (DataRecordType::Float, 4) => DataRecordValue::F32(reader.read_type(endian)?),
             (DataRecordType::Float, 8) => DataRecordValue::F64(reader.read_type(endian)?),
@@ -473,17 +1949,27 @@ impl BinRead for DataRecordValue {
             (DataRecordType::Bytes, _) => {
                 DataRecordValue::Bytes(read_variable_length(reader, endian, length)?)
             }
-            (DataRecordType::String, _) => DataRecordValue::String(
-                match String::from_utf8(read_variable_length(reader, endian, length)?) {
+            (DataRecordType::String, _) => {
+                let bytes = read_variable_length(reader, endian, length)?;
+                let mut string = match String::from_utf8(bytes) {
                     Ok(s) => s,
-                    Err(e) => {
-                        return Err(binrw::Error::Custom {
-                            pos: reader.stream_position()?,
-                            err: Box::new(e),
-                        });
-                    }
-                },
-            ),
+                    Err(e) => match ctx.options.strings {
+                        StringPolicy::Strict => {
+                            return Err(IpfixError::InvalidUtf8(e.utf8_error())
+                                .into_binrw_error(reader.stream_position()?));
+                        }
+                        StringPolicy::Lossy => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+                        // kept whole, so that it is written back to the
+                        // field's length
+                        StringPolicy::Bytes => return Ok(DataRecordValue::Bytes(e.into_bytes())),
+                    },
+                };
+                // a trimmed string is NUL padded again when it is written
+                if length != u16::MAX && ctx.options.trim_trailing_nul {
+                    string.truncate(string.trim_end_matches('\0').len());
+                }
+                DataRecordValue::String(string)
+            }
 
             (DataRecordType::DateTimeSeconds, 4) => {
                 DataRecordValue::DateTimeSeconds(reader.read_type(endian)?)
@@ -508,24 +1994,85 @@ impl BinRead for DataRecordValue {
             (DataRecordType::Ipv6Addr, 16) => {
                 DataRecordValue::Ipv6Addr(u128::read_be(reader)?.into())
             }
+
+            (DataRecordType::BasicList, _) => DataRecordValue::BasicList(read_list_content(
+                reader,
+                endian,
+                length,
+                ctx,
+                BasicList::read_content,
+            )?),
+            (DataRecordType::SubTemplateList, _) => DataRecordValue::SubTemplateList(
+                read_list_content(reader, endian, length, ctx, SubTemplateList::read_content)?,
+            ),
+            (DataRecordType::SubTemplateMultiList, _) => {
+                DataRecordValue::SubTemplateMultiList(read_list_content(
+                    reader,
+                    endian,
+                    length,
+                    ctx,
+                    SubTemplateMultiList::read_content,
+                )?)
+            }
+            _ if ctx.options.raw_fallback => DataRecordValue::Raw {
+                ty,
+                bytes: read_variable_length(reader, endian, length)?,
+            },
             _ => Err(IpfixError::InvalidFieldSpecLength { ty, length }
                 .into_binrw_error(reader.stream_position()?))?,
         })
     }
 }
 
-fn read_u40<R: Read + Seek>(reader: &mut R) -> BinResult<u64> {
-    let mut buf = [0u8; 5];
-    reader.read_exact(&mut buf)?;
+/// Read an `N` byte big-endian unsigned integer (reduced-size encoding, RFC 7011 section 6.2)
+fn read_reduced_unsigned<R: Read + Seek, const N: usize>(reader: &mut R) -> BinResult<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf[8 - N..])?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Read an `N` byte big-endian signed integer, sign-extending it to 64 bits
+fn read_reduced_signed<R: Read + Seek, const N: usize>(reader: &mut R) -> BinResult<i64> {
+    let unsigned = read_reduced_unsigned::<_, N>(reader)?;
+    let shift = 64 - 8 * N as u32;
+    Ok(((unsigned << shift) as i64) >> shift)
+}
+
+/// Check that `value` fits in a `length` byte unsigned integer
+fn unsigned_in_range(value: u64, length: usize) -> Result<(), IpfixError> {
+    if length < 8 && value >> (8 * length) != 0 {
+        return Err(IpfixError::ValueOutOfRange {
+            value: value.into(),
+            length: length as u16,
+        });
+    }
+    Ok(())
+}
+
+/// Check that `value` fits in a `length` byte two's complement integer
+fn signed_in_range(value: i64, length: usize) -> Result<(), IpfixError> {
+    let shift = 64 - 8 * length.min(8) as u32;
+    if (value << shift) >> shift != value {
+        return Err(IpfixError::ValueOutOfRange {
+            value: value.into(),
+            length: length as u16,
+        });
+    }
+    Ok(())
+}
 
-    // Convert 5 bytes to u64, maintaining network byte order (big-endian)
-    let value = ((buf[0] as u64) << 32)
-        | ((buf[1] as u64) << 24)
-        | ((buf[2] as u64) << 16)
-        | ((buf[3] as u64) << 8)
-        | (buf[4] as u64);
+/// Encode `value` as an `N` byte big-endian unsigned integer
+fn reduced_unsigned<const N: usize>(value: &(impl Copy + Into<u64>)) -> BinResult<[u8; N]> {
+    let value: u64 = (*value).into();
+    unsigned_in_range(value, N).map_err(|e| e.into_binrw_error(0))?;
+    Ok(value.to_be_bytes()[8 - N..].try_into().unwrap())
+}
 
-    Ok(value)
+/// Encode `value` as an `N` byte big-endian two's complement integer
+fn reduced_signed<const N: usize>(value: &(impl Copy + Into<i64>)) -> BinResult<[u8; N]> {
+    let value: i64 = (*value).into();
+    signed_in_range(value, N).map_err(|e| e.into_binrw_error(0))?;
+    Ok(value.to_be_bytes()[8 - N..].try_into().unwrap())
 }
 
 #[cfg(test)]
@@ -596,6 +2143,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::expect_fun_call)]
     fn test_u40_roundtrip() {
         let test_values = vec![
             0u64,
@@ -636,4 +2184,171 @@ mod tests {
             assert_eq!(reader.position(), 5, "Should read exactly 5 bytes");
         }
     }
+
+    #[test]
+    fn test_reduced_size_roundtrip() {
+        let cases = vec![
+            (
+                DataRecordType::UnsignedInt,
+                3,
+                DataRecordValue::U24(0xAB_CDEF),
+                vec![0xAB, 0xCD, 0xEF],
+            ),
+            (
+                DataRecordType::UnsignedInt,
+                6,
+                DataRecordValue::U48(0x0102_0304_0506),
+                vec![1, 2, 3, 4, 5, 6],
+            ),
+            (
+                DataRecordType::UnsignedInt,
+                7,
+                DataRecordValue::U56(0xFF_FFFF_FFFF_FFFF),
+                vec![0xFF; 7],
+            ),
+            (
+                DataRecordType::SignedInt,
+                3,
+                DataRecordValue::I24(-1),
+                vec![0xFF; 3],
+            ),
+            (
+                DataRecordType::SignedInt,
+                3,
+                DataRecordValue::I24(0x7F_FFFF),
+                vec![0x7F, 0xFF, 0xFF],
+            ),
+            (
+                DataRecordType::SignedInt,
+                5,
+                DataRecordValue::I40(-0x80_0000_0000),
+                vec![0x80, 0, 0, 0, 0],
+            ),
+            (
+                DataRecordType::SignedInt,
+                6,
+                DataRecordValue::I48(-2),
+                vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE],
+            ),
+            (
+                DataRecordType::SignedInt,
+                7,
+                DataRecordValue::I56(1),
+                vec![0, 0, 0, 0, 0, 0, 1],
+            ),
+        ];
+
+        for (ty, length, value, bytes) in cases {
+            let mut reader = Cursor::new(bytes.clone());
+            let read_value = DataRecordValue::read_options(&mut reader, Endian::Big, (ty, length))
+                .unwrap_or_else(|_| panic!("Failed to read {value:?}"));
+            assert_eq!(read_value, value);
+            assert_eq!(reader.position(), u64::from(length));
+
+            let mut writer = Cursor::new(Vec::new());
+            value
+                .write_options(&mut writer, Endian::Big, (length,))
+                .unwrap_or_else(|_| panic!("Failed to write {value:?}"));
+            assert_eq!(writer.into_inner(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_reduced_size_out_of_range() {
+        for value in [
+            DataRecordValue::U24(0x100_0000),
+            DataRecordValue::U56(0x100_0000_0000_0000),
+            DataRecordValue::I24(0x80_0000),
+            DataRecordValue::I24(-0x80_0001),
+            DataRecordValue::I48(0x8000_0000_0000),
+        ] {
+            let mut writer = Cursor::new(Vec::new());
+            let result = value.write_options(&mut writer, Endian::Big, (0,));
+            assert!(result.is_err(), "Should fail to write {value:?}");
+        }
+    }
+
+    #[test]
+    fn test_coerce() {
+        let cases = vec![
+            (
+                DataRecordValue::U64(0xAB_CDEF),
+                DataRecordType::UnsignedInt,
+                3,
+                DataRecordValue::U24(0xAB_CDEF),
+            ),
+            (
+                DataRecordValue::U8(0x7F),
+                DataRecordType::SignedInt,
+                8,
+                DataRecordValue::I64(0x7F),
+            ),
+            (
+                DataRecordValue::F64(0.5),
+                DataRecordType::Float,
+                4,
+                DataRecordValue::F32(0.5),
+            ),
+            (
+                DataRecordValue::DateTimeSeconds(1),
+                DataRecordType::DateTimeMilliseconds,
+                8,
+                DataRecordValue::DateTimeMilliseconds(1000),
+            ),
+            (
+                DataRecordValue::DateTimeMilliseconds(2000),
+                DataRecordType::DateTimeSeconds,
+                4,
+                DataRecordValue::DateTimeSeconds(2),
+            ),
+            // the sub-second part would be lost, so left for `check`
+            (
+                DataRecordValue::DateTimeMilliseconds(1999),
+                DataRecordType::DateTimeSeconds,
+                4,
+                DataRecordValue::DateTimeMilliseconds(1999),
+            ),
+            (
+                DataRecordValue::DateTimeSeconds(1),
+                DataRecordType::DateTimeMicroseconds,
+                8,
+                DataRecordValue::DateTimeMicroseconds(NtpTimestamp::new(
+                    NtpTimestamp::UNIX_EPOCH_OFFSET + 1,
+                    0,
+                )),
+            ),
+            (
+                DataRecordValue::DateTimeMilliseconds(1500),
+                DataRecordType::DateTimeNanoseconds,
+                8,
+                DataRecordValue::DateTimeNanoseconds(NtpTimestamp::new(
+                    NtpTimestamp::UNIX_EPOCH_OFFSET + 1,
+                    1 << 31,
+                )),
+            ),
+        ];
+        for (value, ty, length, expected) in cases {
+            assert_eq!(value.coerce(Some(ty), length).unwrap().as_ref(), &expected);
+        }
+
+        assert!(matches!(
+            DataRecordValue::I16(-129).coerce(Some(DataRecordType::SignedInt), 1),
+            Err(IpfixError::ValueOutOfRange {
+                value: -129,
+                length: 1
+            })
+        ));
+        assert!(matches!(
+            DataRecordValue::F64(1e300).coerce(Some(DataRecordType::Float), 4),
+            Err(IpfixError::FloatOutOfRange { length: 4, .. })
+        ));
+        assert!(matches!(
+            DataRecordValue::DateTimeMilliseconds(1999).check(
+                Some(DataRecordType::DateTimeSeconds),
+                4,
+                Validation::Permissive
+            ),
+            Err(IpfixError::FieldLengthMismatch { length: 4, .. })
+        ));
+    }
 }
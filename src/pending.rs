@@ -0,0 +1,216 @@
+//! Buffering of data sets that arrive before their template, e.g. after an
+//! exporter or collector restart
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::Error,
+    information_elements::Formatter,
+    parser::{DataRecord, FieldContext, IpfixError, ParseOptions, Records, Set},
+    template_store::{Template, TemplateStorage},
+};
+
+/// Limits on the data held by a `BufferedTemplateStore`. When a new set
+/// would exceed them, the oldest sets are dropped first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingLimits {
+    /// total size of the buffered set contents, in bytes
+    pub max_bytes: usize,
+    /// how long a set may wait for its template
+    pub max_age: Duration,
+}
+
+impl Default for PendingLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 1 << 20,
+            max_age: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PendingSet {
+    content: Vec<u8>,
+    formatter: Rc<Formatter>,
+    options: ParseOptions,
+    received: Instant,
+}
+
+#[derive(Debug, Default)]
+struct PendingSets {
+    /// buffered sets by template id, oldest first
+    sets: HashMap<u16, VecDeque<PendingSet>>,
+    bytes: usize,
+}
+
+impl PendingSets {
+    fn drop_expired(&mut self, max_age: Duration) {
+        let bytes = &mut self.bytes;
+        self.sets.retain(|_, sets| {
+            sets.retain(|set| {
+                let keep = set.received.elapsed() <= max_age;
+                if !keep {
+                    *bytes -= set.content.len();
+                }
+                keep
+            });
+            !sets.is_empty()
+        });
+    }
+
+    fn drop_oldest(&mut self) {
+        let oldest = self
+            .sets
+            .iter()
+            .filter_map(|(template_id, sets)| Some((*template_id, sets.front()?.received)))
+            .min_by_key(|(_, received)| *received);
+        if let Some((template_id, _)) = oldest {
+            let sets = self.sets.get_mut(&template_id).unwrap();
+            if let Some(set) = sets.pop_front() {
+                self.bytes -= set.content.len();
+            }
+            if sets.is_empty() {
+                self.sets.remove(&template_id);
+            }
+        }
+    }
+}
+
+/// A `TemplateStorage` that holds on to data sets whose template is not
+/// known yet, instead of failing with `IpfixError::MissingTemplate`.
+///
+/// When the template is inserted, the buffered sets are decoded and can be
+/// collected with `take_released`. Messages containing a buffered set have a
+/// `Records::Pending` in its place.
+#[derive(Debug)]
+pub struct BufferedTemplateStore<S> {
+    inner: S,
+    limits: PendingLimits,
+    pending: RefCell<PendingSets>,
+    released: RefCell<Vec<Result<Set, Error>>>,
+}
+
+impl<S: TemplateStorage> BufferedTemplateStore<S> {
+    pub fn new(inner: S, limits: PendingLimits) -> Self {
+        Self {
+            inner,
+            limits,
+            pending: RefCell::default(),
+            released: RefCell::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Number of sets waiting for a template
+    pub fn pending_sets(&self) -> usize {
+        self.pending.borrow().sets.values().map(VecDeque::len).sum()
+    }
+
+    /// Total size of the sets waiting for a template, in bytes
+    pub fn pending_bytes(&self) -> usize {
+        self.pending.borrow().bytes
+    }
+
+    /// Take the sets decoded since the last call, in the order their
+    /// templates arrived
+    pub fn take_released(&self) -> Vec<Result<Set, Error>> {
+        self.released.take()
+    }
+
+    fn release(&self, template_id: u16, template: &Template) {
+        let sets = {
+            let mut pending = self.pending.borrow_mut();
+            pending.drop_expired(self.limits.max_age);
+            match pending.sets.remove(&template_id) {
+                Some(sets) => {
+                    pending.bytes -= sets.iter().map(|set| set.content.len()).sum::<usize>();
+                    sets
+                }
+                None => return,
+            }
+        };
+
+        for set in sets {
+            let ctx = FieldContext {
+                templates: Some(self),
+                formatter: Some(&set.formatter),
+                options: set.options,
+                ..Default::default()
+            };
+            let released = DataRecord::read_data_set(&set.content, template_id, template, ctx)
+                .map(|(data, padding)| Set {
+                    records: Records::Data {
+                        set_id: template_id,
+                        data,
+                    },
+                    padding: Some(padding),
+                })
+                // offsets are from the start of the set, as its message is gone
+                .map_err(|e| Error::from(e).in_set(template_id, 4));
+            self.released.borrow_mut().push(released);
+        }
+    }
+}
+
+impl<S: TemplateStorage> TemplateStorage for BufferedTemplateStore<S> {
+    fn get_template(&self, template_id: u16) -> Option<Template> {
+        self.inner.get_template(template_id)
+    }
+
+    fn insert_template(&self, template_id: u16, template: Template) {
+        self.inner.insert_template(template_id, template.clone());
+        self.release(template_id, &template);
+    }
+
+    fn remove_template(&self, template_id: u16) {
+        self.inner.remove_template(template_id);
+    }
+
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        self.inner.retain_templates(f);
+    }
+
+    fn template_count(&self) -> usize {
+        self.inner.template_count()
+    }
+
+    fn buffer_data_set(
+        &self,
+        set_id: u16,
+        content: &[u8],
+        formatter: &Rc<Formatter>,
+        options: ParseOptions,
+    ) -> Result<(), IpfixError> {
+        if content.len() > self.limits.max_bytes {
+            return Err(IpfixError::MissingTemplate(set_id));
+        }
+
+        let mut pending = self.pending.borrow_mut();
+        pending.drop_expired(self.limits.max_age);
+        while pending.bytes + content.len() > self.limits.max_bytes {
+            pending.drop_oldest();
+        }
+
+        pending.bytes += content.len();
+        pending
+            .sets
+            .entry(set_id)
+            .or_default()
+            .push_back(PendingSet {
+                content: content.to_vec(),
+                formatter: formatter.clone(),
+                options,
+                received: Instant::now(),
+            });
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! Resolution of flow start and end times sent relative to the exporter's
+//! system init time or the message export time
+
+use std::hash::Hash;
+use std::time::{Duration, SystemTime};
+
+use ahash::HashMap;
+
+use crate::parser::{DataRecord, DataRecordKey, Message};
+use crate::timestamp::seconds_to_system_time;
+
+/// How a `ResolvedTime` was found
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ResolutionMethod {
+    /// from an absolute timestamp, e.g. `flowStartMilliseconds`
+    Absolute,
+    /// from `flowStartSysUpTime`/`flowEndSysUpTime` and the
+    /// `systemInitTimeMilliseconds` of the record or its exporter
+    SystemInitTime,
+    /// from `flowStartDeltaMicroseconds`/`flowEndDeltaMicroseconds` and the
+    /// export time of the message
+    ExportTimeDelta,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ResolvedTime {
+    pub time: SystemTime,
+    pub method: ResolutionMethod,
+}
+
+/// The start and end of a flow, where they could be resolved
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct FlowTimes {
+    pub start: Option<ResolvedTime>,
+    pub end: Option<ResolvedTime>,
+}
+
+/// The fields that can give the start or end of a flow, most precise first
+struct FlowTimeFields {
+    absolute: [&'static str; 4],
+    sys_up_time: &'static str,
+    delta_microseconds: &'static str,
+}
+
+const START: FlowTimeFields = FlowTimeFields {
+    absolute: [
+        "flowStartNanoseconds",
+        "flowStartMicroseconds",
+        "flowStartMilliseconds",
+        "flowStartSeconds",
+    ],
+    sys_up_time: "flowStartSysUpTime",
+    delta_microseconds: "flowStartDeltaMicroseconds",
+};
+
+const END: FlowTimeFields = FlowTimeFields {
+    absolute: [
+        "flowEndNanoseconds",
+        "flowEndMicroseconds",
+        "flowEndMilliseconds",
+        "flowEndSeconds",
+    ],
+    sys_up_time: "flowEndSysUpTime",
+    delta_microseconds: "flowEndDeltaMicroseconds",
+};
+
+const SYSTEM_INIT_TIME: DataRecordKey = DataRecordKey::Str("systemInitTimeMilliseconds");
+
+/// Tracks the `systemInitTimeMilliseconds` of each exporter, identified by
+/// a key `K` chosen by the caller (e.g. its address and observation domain),
+/// to resolve the relative flow times of its records
+#[derive(Debug)]
+pub struct TimestampResolver<K> {
+    system_init_times: HashMap<K, SystemTime>,
+}
+
+impl<K> Default for TimestampResolver<K> {
+    fn default() -> Self {
+        Self {
+            system_init_times: HashMap::default(),
+        }
+    }
+}
+
+impl<K: Hash + Eq> TimestampResolver<K> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn system_init_time(&self, exporter: &K) -> Option<SystemTime> {
+        self.system_init_times.get(exporter).copied()
+    }
+
+    pub fn set_system_init_time(&mut self, exporter: K, time: SystemTime) {
+        self.system_init_times.insert(exporter, time);
+    }
+
+    /// Record the system init time of `exporter` if `record` has one, as
+    /// options records usually do. Returns whether it did
+    pub fn observe(&mut self, exporter: K, record: &DataRecord) -> bool {
+        match record
+            .get(&SYSTEM_INIT_TIME)
+            .and_then(|v| v.to_system_time())
+        {
+            Some(time) => {
+                self.set_system_init_time(exporter, time);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// `observe` each data record of a message from `exporter`
+    pub fn observe_message(&mut self, exporter: K, msg: &Message)
+    where
+        K: Clone,
+    {
+        for record in msg.iter_data_records() {
+            self.observe(exporter.clone(), record);
+        }
+    }
+
+    /// Resolve the start and end of the flow described by `record`, from
+    /// `exporter`, in a message exported at `export_time`. Absolute
+    /// timestamps are preferred, then times relative to the system init
+    /// time of the record or else the exporter, then times relative to
+    /// the export time
+    pub fn resolve(&self, exporter: &K, export_time: SystemTime, record: &DataRecord) -> FlowTimes {
+        let system_init_time = record
+            .get(&SYSTEM_INIT_TIME)
+            .and_then(|v| v.to_system_time())
+            .or_else(|| self.system_init_time(exporter));
+        FlowTimes {
+            start: resolve_field(&START, record, system_init_time, export_time),
+            end: resolve_field(&END, record, system_init_time, export_time),
+        }
+    }
+
+    /// `resolve` each data record of a message from `exporter`
+    pub fn resolve_message<'a>(
+        &'a self,
+        exporter: &'a K,
+        msg: &'a Message,
+    ) -> impl Iterator<Item = (&'a DataRecord, FlowTimes)> + 'a {
+        let export_time = seconds_to_system_time(msg.export_time);
+        msg.iter_data_records()
+            .map(move |record| (record, self.resolve(exporter, export_time, record)))
+    }
+}
+
+fn resolve_field(
+    fields: &FlowTimeFields,
+    record: &DataRecord,
+    system_init_time: Option<SystemTime>,
+    export_time: SystemTime,
+) -> Option<ResolvedTime> {
+    let unsigned = |name| {
+        let value = record.get(&DataRecordKey::Str(name))?.as_i128()?;
+        u64::try_from(value).ok()
+    };
+    let resolved = |time, method| Some(ResolvedTime { time, method });
+
+    if let Some(time) = fields
+        .absolute
+        .iter()
+        .find_map(|name| record.get(&DataRecordKey::Str(name))?.to_system_time())
+    {
+        return resolved(time, ResolutionMethod::Absolute);
+    }
+    if let (Some(init), Some(millis)) = (system_init_time, unsigned(fields.sys_up_time)) {
+        return resolved(
+            init.checked_add(Duration::from_millis(millis))?,
+            ResolutionMethod::SystemInitTime,
+        );
+    }
+    if let Some(micros) = unsigned(fields.delta_microseconds) {
+        return resolved(
+            export_time.checked_sub(Duration::from_micros(micros))?,
+            ResolutionMethod::ExportTimeDelta,
+        );
+    }
+    None
+}
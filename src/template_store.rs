@@ -1,14 +1,17 @@
 use std::{
     cell::RefCell,
     collections::HashMap,
+    hash::Hash,
     rc::Rc,
     sync::{Arc, RwLock},
 };
 
 use crate::{
+    error::{Error, Limit},
     information_elements::Formatter,
     parser::{
-        DataRecordKey, DataRecordType, FieldSpecifier, OptionsTemplateRecord, TemplateRecord,
+        DataRecordKey, DataRecordType, FieldSpecifier, IpfixError, MessageHeader,
+        OptionsTemplateRecord, ParseLimits, ParseOptions, TemplateRecord, TemplateWithdrawalRecord,
     },
 };
 
@@ -25,7 +28,7 @@ impl ExpandedFieldSpecifier {
     /// Look up a DataRecordKey and DataRecordType for this
     /// information element from the formatter. If not present,
     /// returns Unrecognized/Bytes.
-    fn from_field_spec(field_spec: &FieldSpecifier, formatter: &Formatter) -> Self {
+    pub(crate) fn from_field_spec(field_spec: &FieldSpecifier, formatter: &Formatter) -> Self {
         let (name, ty) = match formatter.get(&(
             field_spec.enterprise_number.unwrap_or(0),
             field_spec.information_element_identifier,
@@ -51,15 +54,81 @@ impl ExpandedFieldSpecifier {
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum Template {
     Template(Vec<ExpandedFieldSpecifier>),
-    OptionsTemplate(Vec<ExpandedFieldSpecifier>),
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.4.2.2>
+    OptionsTemplate {
+        /// the first `scope_field_count` field specifiers are scope fields
+        scope_field_count: u16,
+        field_specifiers: Vec<ExpandedFieldSpecifier>,
+    },
+}
+
+impl Template {
+    /// All field specifiers, including any scope fields
+    pub fn field_specifiers(&self) -> &[ExpandedFieldSpecifier] {
+        match self {
+            Template::Template(field_specifiers) => field_specifiers,
+            Template::OptionsTemplate {
+                field_specifiers, ..
+            } => field_specifiers,
+        }
+    }
+
+    /// Length of the shortest possible record: the fixed length fields,
+    /// plus a 1 byte length prefix for each variable length field. Fewer
+    /// bytes at the end of a data set are padding
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.3.1>
+    pub fn min_record_length(&self) -> usize {
+        self.field_specifiers()
+            .iter()
+            .map(|field_spec| match field_spec.field_length {
+                u16::MAX => 1,
+                length => usize::from(length),
+            })
+            .sum()
+    }
+
+    /// Number of scope fields, always 0 for a non-options Template
+    pub fn scope_field_count(&self) -> u16 {
+        match self {
+            Template::Template(_) => 0,
+            Template::OptionsTemplate {
+                scope_field_count, ..
+            } => *scope_field_count,
+        }
+    }
 }
 
 pub trait TemplateStorage: std::fmt::Debug {
     fn get_template(&self, template_id: u16) -> Option<Template>;
     fn insert_template(&self, template_id: u16, template: Template);
+    fn remove_template(&self, template_id: u16);
+    /// Remove all templates for which `f` returns false
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool);
+
+    /// Number of templates and options templates in the store
+    fn template_count(&self) -> usize {
+        let mut count = 0;
+        self.retain_templates(&mut |_, _| {
+            count += 1;
+            true
+        });
+        count
+    }
 
-    fn insert_template_records(&self, template_records: &[TemplateRecord], formatter: &Formatter) {
+    /// Insert templates, failing at the first that would exceed `limits`
+    fn insert_template_records(
+        &self,
+        template_records: &[TemplateRecord],
+        formatter: &Formatter,
+        limits: ParseLimits,
+    ) -> Result<(), IpfixError> {
         for template in template_records {
+            check_limits(
+                self,
+                template.template_id,
+                template.field_specifiers.len(),
+                limits,
+            )?;
             let expanded_template = Template::Template(
                 template
                     .field_specifiers
@@ -72,29 +141,108 @@ pub trait TemplateStorage: std::fmt::Debug {
 
             self.insert_template(template.template_id, expanded_template);
         }
+        Ok(())
     }
 
-    // TODO: these should probably be treated differently
+    /// Insert options templates, after checking that all of their scope
+    /// field counts are valid. Fails at the first that would exceed the
+    /// limits of `options`
     fn insert_options_template_records(
         &self,
         template_records: &[OptionsTemplateRecord],
         formatter: &Formatter,
-    ) {
+        options: ParseOptions,
+    ) -> Result<(), IpfixError> {
+        // https://www.rfc-editor.org/rfc/rfc7011#section-3.4.2.2
         for template in template_records {
-            let expanded_template = Template::OptionsTemplate(
-                template
+            let field_count = template.field_specifiers.len();
+            if (template.scope_field_count == 0 && !options.allow_zero_scope_field_count)
+                || usize::from(template.scope_field_count) > field_count
+            {
+                return Err(IpfixError::InvalidScopeFieldCount {
+                    template_id: template.template_id,
+                    scope_field_count: template.scope_field_count,
+                    field_count,
+                });
+            }
+        }
+
+        for template in template_records {
+            check_limits(
+                self,
+                template.template_id,
+                template.field_specifiers.len(),
+                options.limits,
+            )?;
+            let expanded_template = Template::OptionsTemplate {
+                scope_field_count: template.scope_field_count,
+                field_specifiers: template
                     .field_specifiers
                     .iter()
                     .map(|field_spec| {
                         ExpandedFieldSpecifier::from_field_spec(field_spec, formatter)
                     })
                     .collect(),
-            );
+            };
             self.insert_template(template.template_id, expanded_template);
         }
+        Ok(())
+    }
+
+    /// Called with the content of a data set whose template is unknown.
+    /// Stores that can hold on to the set until its template arrives
+    /// should do so and return `Ok`, see `BufferedTemplateStore`
+    fn buffer_data_set(
+        &self,
+        set_id: u16,
+        _content: &[u8],
+        _formatter: &Rc<Formatter>,
+        _options: ParseOptions,
+    ) -> Result<(), IpfixError> {
+        Err(IpfixError::MissingTemplate(set_id))
+    }
+
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-8.1>
+    fn withdraw_template_records(&self, set_id: u16, withdrawals: &[TemplateWithdrawalRecord]) {
+        for withdrawal in withdrawals {
+            if withdrawal.withdraws_all() {
+                let options = set_id == 3;
+                self.retain_templates(&mut |_, template| {
+                    matches!(template, Template::OptionsTemplate { .. }) != options
+                });
+            } else {
+                self.remove_template(withdrawal.template_id);
+            }
+        }
     }
 }
 
+/// Check that a template of `field_count` fields can be inserted as
+/// `template_id` without exceeding `limits`
+fn check_limits<T: TemplateStorage + ?Sized>(
+    templates: &T,
+    template_id: u16,
+    field_count: usize,
+    limits: ParseLimits,
+) -> Result<(), IpfixError> {
+    if field_count > limits.max_fields {
+        return Err(IpfixError::LimitExceeded {
+            limit: Limit::Fields,
+            max: limits.max_fields,
+        });
+    }
+    // replacing a template does not add to the store
+    if templates.template_count() >= limits.max_templates
+        && templates.get_template(template_id).is_none()
+    {
+        return Err(IpfixError::LimitExceeded {
+            limit: Limit::Templates,
+            max: limits.max_templates,
+        });
+    }
+    Ok(())
+}
+
 impl<S: ::std::hash::BuildHasher> TemplateStorage for RefCell<HashMap<u16, Template, S>> {
     fn get_template(&self, template_id: u16) -> Option<Template> {
         self.borrow().get(&template_id).cloned()
@@ -102,6 +250,16 @@ impl<S: ::std::hash::BuildHasher> TemplateStorage for RefCell<HashMap<u16, Templ
     fn insert_template(&self, template_id: u16, template: Template) {
         self.borrow_mut().insert(template_id, template);
     }
+    fn remove_template(&self, template_id: u16) {
+        self.borrow_mut().remove(&template_id);
+    }
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        self.borrow_mut()
+            .retain(|template_id, template| f(*template_id, template));
+    }
+    fn template_count(&self) -> usize {
+        self.borrow().len()
+    }
 }
 
 impl<S: ::std::hash::BuildHasher> TemplateStorage for Arc<RwLock<HashMap<u16, Template, S>>> {
@@ -111,6 +269,331 @@ impl<S: ::std::hash::BuildHasher> TemplateStorage for Arc<RwLock<HashMap<u16, Te
     fn insert_template(&self, template_id: u16, template: Template) {
         self.write().unwrap().insert(template_id, template);
     }
+    fn remove_template(&self, template_id: u16) {
+        self.write().unwrap().remove(&template_id);
+    }
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        self.write()
+            .unwrap()
+            .retain(|template_id, template| f(*template_id, template));
+    }
+    fn template_count(&self) -> usize {
+        self.read().unwrap().len()
+    }
 }
 
 pub type TemplateStore = Rc<dyn TemplateStorage>;
+
+/// Templates are scoped to a transport session and an Observation Domain,
+/// so exporters, or the Observation Domains of one exporter, may reuse the
+/// same template ids
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-8>
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub struct TemplateScope<K> {
+    /// identifies the session, chosen by the caller, e.g. the exporter's
+    /// address
+    pub session: K,
+    pub observation_domain_id: u32,
+}
+
+/// A `TemplateStore` for each `TemplateScope`. A scope is only kept once a
+/// message in it has parsed, and the templates of a message are only
+/// inserted into its scope if the whole message parses. See
+/// `parse_ipfix_message_scoped`
+pub struct ScopedTemplateStore<K> {
+    stores: Rc<RefCell<HashMap<TemplateScope<K>, TemplateStore>>>,
+    new_store: Box<dyn Fn() -> TemplateStore>,
+}
+
+impl<K: Hash + Eq + Clone + 'static> ScopedTemplateStore<K> {
+    /// Use `new_store` to create the store for each scope, e.g. a
+    /// `BufferedTemplateStore`
+    pub fn new(new_store: impl Fn() -> TemplateStore + 'static) -> Self {
+        Self {
+            stores: Rc::new(RefCell::new(HashMap::new())),
+            new_store: Box::new(new_store),
+        }
+    }
+
+    /// The store for a session and Observation Domain, created if needed,
+    /// unless the session already has `limits.max_domains` of them. The
+    /// templates of all of a session's scopes count towards
+    /// `limits.max_templates`
+    pub fn scope(
+        &self,
+        session: &K,
+        observation_domain_id: u32,
+        limits: ParseLimits,
+    ) -> Result<TemplateStore, IpfixError> {
+        let (store, created) = self.open(session, observation_domain_id, limits)?;
+        if created {
+            self.keep(&store);
+        }
+        Ok(Rc::new(store))
+    }
+
+    /// Parse a message with the store of `session` and the message's
+    /// Observation Domain. Changes to the templates of the scope are staged
+    /// while parsing, and only made, or a new scope kept, if `parse`
+    /// succeeds
+    pub(crate) fn parse_in_scope<R>(
+        &self,
+        session: &K,
+        header: &MessageHeader,
+        limits: ParseLimits,
+        parse: impl FnOnce(TemplateStore) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        let (store, created) = self
+            .open(session, header.observation_domain_id, limits)
+            // the offset of the Observation Domain ID in the header
+            .map_err(|e| Error::new(e, 12))?;
+        let scope = Rc::new(SessionStore {
+            staged: Some(RefCell::default()),
+            ..store
+        });
+        let result = parse(scope.clone())?;
+        scope.commit();
+        if created {
+            self.keep(&scope);
+        }
+        Ok(result)
+    }
+
+    /// The store for a scope, and whether it is new
+    fn open(
+        &self,
+        session: &K,
+        observation_domain_id: u32,
+        limits: ParseLimits,
+    ) -> Result<(SessionStore<K>, bool), IpfixError> {
+        let scope = TemplateScope {
+            session: session.clone(),
+            observation_domain_id,
+        };
+        let (inner, created) = match self.stores.borrow().get(&scope) {
+            Some(inner) => (inner.clone(), false),
+            None => {
+                let domains = self.scopes_of(session);
+                if domains >= limits.max_domains {
+                    return Err(IpfixError::LimitExceeded {
+                        limit: Limit::Domains,
+                        max: limits.max_domains,
+                    });
+                }
+                ((self.new_store)(), true)
+            }
+        };
+        let store = SessionStore {
+            stores: self.stores.clone(),
+            scope,
+            inner,
+            staged: None,
+        };
+        Ok((store, created))
+    }
+
+    fn keep(&self, store: &SessionStore<K>) {
+        self.stores
+            .borrow_mut()
+            .insert(store.scope.clone(), store.inner.clone());
+    }
+
+    fn scopes_of(&self, session: &K) -> usize {
+        self.stores
+            .borrow()
+            .keys()
+            .filter(|scope| scope.session == *session)
+            .count()
+    }
+
+    /// The store for a session and Observation Domain, if it has been used
+    pub fn get(&self, session: &K, observation_domain_id: u32) -> Option<TemplateStore> {
+        let scope = TemplateScope {
+            session: session.clone(),
+            observation_domain_id,
+        };
+        let inner = self.stores.borrow().get(&scope)?.clone();
+        Some(Rc::new(SessionStore {
+            stores: self.stores.clone(),
+            scope,
+            inner,
+            staged: None,
+        }))
+    }
+
+    pub fn get_template(
+        &self,
+        session: &K,
+        observation_domain_id: u32,
+        template_id: u16,
+    ) -> Option<Template> {
+        self.get(session, observation_domain_id)?
+            .get_template(template_id)
+    }
+
+    /// Drop the templates of all Observation Domains of a session, e.g.
+    /// when it is closed
+    pub fn remove_session(&self, session: &K) {
+        self.stores
+            .borrow_mut()
+            .retain(|scope, _| scope.session != *session);
+    }
+
+    /// All scopes that have been used
+    pub fn scopes(&self) -> Vec<TemplateScope<K>> {
+        self.stores.borrow().keys().cloned().collect()
+    }
+}
+
+impl<K: Hash + Eq + Clone + 'static> Default for ScopedTemplateStore<K> {
+    fn default() -> Self {
+        Self::new(|| Rc::new(RefCell::new(HashMap::<u16, Template>::new())))
+    }
+}
+
+impl<K: std::fmt::Debug> std::fmt::Debug for ScopedTemplateStore<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScopedTemplateStore")
+            .field("stores", &self.stores)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The store of one scope, whose `template_count` is that of all of the
+/// scopes of its session, so that `ParseLimits::max_templates` applies to
+/// the session
+struct SessionStore<K> {
+    stores: Rc<RefCell<HashMap<TemplateScope<K>, TemplateStore>>>,
+    scope: TemplateScope<K>,
+    inner: TemplateStore,
+    /// changes not yet made to `inner`, while a message is parsed
+    staged: Option<RefCell<StagedTemplates>>,
+}
+
+/// Templates inserted or removed while parsing a message, which are only
+/// made to the scope once the whole message has parsed
+#[derive(Default)]
+struct StagedTemplates {
+    /// the template for each changed id, or `None` if it was removed
+    templates: HashMap<u16, Option<Template>>,
+    /// changed ids, in the order they were first changed
+    order: Vec<u16>,
+    /// change to the number of templates in the scope
+    count: isize,
+}
+
+impl<K: Hash + Eq> SessionStore<K> {
+    fn stage(
+        &self,
+        staged: &RefCell<StagedTemplates>,
+        template_id: u16,
+        template: Option<Template>,
+    ) {
+        let present = self.get_template(template_id).is_some();
+        let mut staged = staged.borrow_mut();
+        staged.count += isize::from(template.is_some()) - isize::from(present);
+        if staged.templates.insert(template_id, template).is_none() {
+            staged.order.push(template_id);
+        }
+    }
+
+    /// Make the staged changes to the scope
+    fn commit(&self) {
+        let Some(staged) = &self.staged else {
+            return;
+        };
+        let StagedTemplates {
+            mut templates,
+            order,
+            ..
+        } = staged.take();
+        for template_id in order {
+            match templates.remove(&template_id) {
+                Some(Some(template)) => self.inner.insert_template(template_id, template),
+                Some(None) => self.inner.remove_template(template_id),
+                None => {}
+            }
+        }
+    }
+}
+
+impl<K> std::fmt::Debug for SessionStore<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionStore")
+            .field("observation_domain_id", &self.scope.observation_domain_id)
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K: Hash + Eq> TemplateStorage for SessionStore<K> {
+    fn get_template(&self, template_id: u16) -> Option<Template> {
+        if let Some(staged) = &self.staged {
+            if let Some(template) = staged.borrow().templates.get(&template_id) {
+                return template.clone();
+            }
+        }
+        self.inner.get_template(template_id)
+    }
+    fn insert_template(&self, template_id: u16, template: Template) {
+        match &self.staged {
+            Some(staged) => self.stage(staged, template_id, Some(template)),
+            None => self.inner.insert_template(template_id, template),
+        }
+    }
+    fn remove_template(&self, template_id: u16) {
+        match &self.staged {
+            Some(staged) => self.stage(staged, template_id, None),
+            None => self.inner.remove_template(template_id),
+        }
+    }
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        let Some(staged) = &self.staged else {
+            return self.inner.retain_templates(f);
+        };
+        let mut removed = Vec::new();
+        {
+            let staged = staged.borrow();
+            self.inner.retain_templates(&mut |template_id, template| {
+                if !staged.templates.contains_key(&template_id) && !f(template_id, template) {
+                    removed.push(template_id);
+                }
+                true
+            });
+            for (&template_id, template) in &staged.templates {
+                if let Some(template) = template {
+                    if !f(template_id, template) {
+                        removed.push(template_id);
+                    }
+                }
+            }
+        }
+        for template_id in removed {
+            self.stage(staged, template_id, None);
+        }
+    }
+    fn template_count(&self) -> usize {
+        let others: usize = self
+            .stores
+            .borrow()
+            .iter()
+            .filter(|(scope, _)| scope.session == self.scope.session && **scope != self.scope)
+            .map(|(_, store)| store.template_count())
+            .sum();
+        let staged = self
+            .staged
+            .as_ref()
+            .map_or(0, |staged| staged.borrow().count);
+        others + self.inner.template_count().saturating_add_signed(staged)
+    }
+    fn buffer_data_set(
+        &self,
+        set_id: u16,
+        content: &[u8],
+        formatter: &Rc<Formatter>,
+        options: ParseOptions,
+    ) -> Result<(), IpfixError> {
+        self.inner
+            .buffer_data_set(set_id, content, formatter, options)
+    }
+}
@@ -0,0 +1,434 @@
+//! Timestamps in the encodings used by IPFIX, and their conversion to
+//! `SystemTime`, and with the `chrono` and `time` features to
+//! `chrono::DateTime<Utc>` and `time::OffsetDateTime`
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use binrw::binrw;
+
+use crate::parser::{DataRecordType, DataRecordValue, Message, MessageHeader};
+
+/// A timestamp in NTP format, used for `dateTimeMicroseconds` and
+/// `dateTimeNanoseconds` fields
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-6.1.9>
+///
+/// The seconds and fraction are kept as they were read, so the timestamp is
+/// written back unchanged. The era is not encoded, so as in RFC 4330
+/// section 3, seconds with the top bit clear are in the era after 2036, and
+/// timestamps cover 1968 to 2104
+/// <https://www.rfc-editor.org/rfc/rfc4330#section-3>
+#[binrw]
+#[brw(big)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Default)]
+pub struct NtpTimestamp {
+    /// seconds since 1900-01-01 00:00 UTC, or with the top bit clear,
+    /// since 2036-02-07 06:28:16 UTC
+    pub seconds: u32,
+    /// fraction of a second, in units of 2^-32 seconds
+    pub fraction: u32,
+}
+
+impl NtpTimestamp {
+    /// Seconds from the NTP epoch (1900) to the Unix epoch (1970)
+    pub const UNIX_EPOCH_OFFSET: u32 = 2_208_988_800;
+
+    /// For `dateTimeMicroseconds`, these bits of the fraction are not used
+    const MICROSECONDS_UNUSED: u32 = 0x7FF;
+
+    /// Set in the seconds of times before 2036-02-07 06:28:16 UTC
+    const ERA_BIT: u32 = 1 << 31;
+
+    pub fn new(seconds: u32, fraction: u32) -> Self {
+        Self { seconds, fraction }
+    }
+
+    /// Nanoseconds since the Unix epoch, rounded to the nearest nanosecond
+    pub fn unix_nanos(&self) -> i64 {
+        self.unix_units(1_000_000_000)
+    }
+
+    /// Microseconds since the Unix epoch, rounded to the nearest microsecond
+    pub fn unix_micros(&self) -> i64 {
+        self.unix_units(1_000_000)
+    }
+
+    /// From nanoseconds since the Unix epoch, if it is between 1968 and 2104
+    pub fn from_unix_nanos(nanos: i64) -> Option<Self> {
+        Self::from_unix_units(nanos, 1_000_000_000)
+    }
+
+    /// From microseconds since the Unix epoch, if it is between 1968 and
+    /// 2104, with the unused bits of the fraction cleared
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-6.1.9>
+    pub fn from_unix_micros(micros: i64) -> Option<Self> {
+        let timestamp = Self::from_unix_units(micros, 1_000_000)?;
+        // round to the nearest fraction without the unused bits
+        let unused = u64::from(Self::MICROSECONDS_UNUSED);
+        let fraction = (u64::from(timestamp.fraction) + unused / 2 + 1) & !unused;
+        Self::from_parts(timestamp.era_seconds(), fraction)
+    }
+
+    /// The 64 bit encoding, seconds in the high half
+    pub fn to_bits(self) -> u64 {
+        u64::from(self.seconds) << 32 | u64::from(self.fraction)
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        Self::new((bits >> 32) as u32, bits as u32)
+    }
+
+    pub fn to_system_time(&self) -> SystemTime {
+        system_time_from_unix_nanos(self.unix_nanos().into())
+    }
+
+    /// From a time between 1968 and 2104
+    pub fn from_system_time(time: SystemTime) -> Option<Self> {
+        Self::from_unix_nanos(unix_nanos(time)?.try_into().ok()?)
+    }
+
+    /// Seconds since 1900, taking seconds with the top bit clear to be in
+    /// the era after 2036
+    fn era_seconds(&self) -> i64 {
+        if self.seconds & Self::ERA_BIT == 0 {
+            i64::from(self.seconds) + (1 << 32)
+        } else {
+            i64::from(self.seconds)
+        }
+    }
+
+    fn unix_units(&self, units_per_second: u64) -> i64 {
+        let seconds = self.era_seconds() - i64::from(Self::UNIX_EPOCH_OFFSET);
+        let units = (u64::from(self.fraction) * units_per_second + (1 << 31)) >> 32;
+        seconds * units_per_second as i64 + units as i64
+    }
+
+    fn from_unix_units(units: i64, units_per_second: u64) -> Option<Self> {
+        let per_second = units_per_second as i64;
+        let seconds = units.div_euclid(per_second) + i64::from(Self::UNIX_EPOCH_OFFSET);
+        let units = units.rem_euclid(per_second) as u64;
+        let fraction = ((units << 32) + units_per_second / 2) / units_per_second;
+        Self::from_parts(seconds, fraction)
+    }
+
+    /// From seconds since 1900 and a fraction that may have rounded up to
+    /// a whole second, if the seconds are in the range of `era_seconds`
+    fn from_parts(seconds: i64, fraction: u64) -> Option<Self> {
+        let seconds = seconds + (fraction >> 32) as i64;
+        let era_start = i64::from(Self::ERA_BIT);
+        if !(era_start..era_start + (1 << 32)).contains(&seconds) {
+            return None;
+        }
+        Some(Self::new(seconds as u32, fraction as u32))
+    }
+}
+
+/// In time order, which is not the order of `seconds` across the eras
+impl PartialOrd for NtpTimestamp {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NtpTimestamp {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.era_seconds(), self.fraction).cmp(&(other.era_seconds(), other.fraction))
+    }
+}
+
+/// `dateTimeSeconds`, e.g. `Message::export_time`, as a `SystemTime`
+pub fn seconds_to_system_time(seconds: u32) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(seconds.into())
+}
+
+/// `dateTimeSeconds`, e.g. `Message::export_time`, from a `SystemTime`
+/// between 1970 and 2106, truncated to the second
+pub fn system_time_to_seconds(time: SystemTime) -> Option<u32> {
+    time.duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs()
+        .try_into()
+        .ok()
+}
+
+/// `dateTimeMilliseconds` as a `SystemTime`
+pub fn millis_to_system_time(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// `dateTimeMilliseconds` from a `SystemTime` after 1970, truncated to the
+/// millisecond
+pub fn system_time_to_millis(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_millis()
+        .try_into()
+        .ok()
+}
+
+/// Nanoseconds since the Unix epoch, negative before it
+fn unix_nanos(time: SystemTime) -> Option<i128> {
+    Some(match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_nanos().try_into().ok()?,
+        Err(e) => -i128::try_from(e.duration().as_nanos()).ok()?,
+    })
+}
+
+fn system_time_from_unix_nanos(nanos: i128) -> SystemTime {
+    let duration = Duration::from_nanos(nanos.unsigned_abs() as u64);
+    if nanos < 0 {
+        UNIX_EPOCH - duration
+    } else {
+        UNIX_EPOCH + duration
+    }
+}
+
+impl DataRecordValue {
+    /// The time of `DateTime*` values
+    pub fn to_system_time(&self) -> Option<SystemTime> {
+        Some(match self {
+            Self::DateTimeSeconds(x) => seconds_to_system_time(*x),
+            Self::DateTimeMilliseconds(x) => millis_to_system_time(*x),
+            // at the precision of the field, as the fraction is rounded
+            Self::DateTimeMicroseconds(x) => {
+                system_time_from_unix_nanos(i128::from(x.unix_micros()) * 1000)
+            }
+            Self::DateTimeNanoseconds(x) => x.to_system_time(),
+            _ => return None,
+        })
+    }
+
+    /// A `DateTime*` value of type `ty`, if `time` is in its range
+    pub fn from_system_time(ty: DataRecordType, time: SystemTime) -> Option<Self> {
+        Some(match ty {
+            DataRecordType::DateTimeSeconds => Self::DateTimeSeconds(system_time_to_seconds(time)?),
+            DataRecordType::DateTimeMilliseconds => {
+                Self::DateTimeMilliseconds(system_time_to_millis(time)?)
+            }
+            DataRecordType::DateTimeMicroseconds => {
+                Self::DateTimeMicroseconds(NtpTimestamp::from_unix_micros(
+                    unix_nanos(time)?.div_euclid(1000).try_into().ok()?,
+                )?)
+            }
+            DataRecordType::DateTimeNanoseconds => {
+                Self::DateTimeNanoseconds(NtpTimestamp::from_system_time(time)?)
+            }
+            _ => return None,
+        })
+    }
+
+    /// The time since the Unix epoch of `DateTime*` values after it
+    pub fn to_duration(&self) -> Option<Duration> {
+        self.to_system_time()?.duration_since(UNIX_EPOCH).ok()
+    }
+
+    /// A `DateTime*` value of type `ty`, `since` the Unix epoch
+    pub fn from_duration(ty: DataRecordType, since: Duration) -> Option<Self> {
+        Self::from_system_time(ty, UNIX_EPOCH.checked_add(since)?)
+    }
+
+    #[cfg(feature = "chrono")]
+    pub fn to_chrono(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.to_system_time().map(Into::into)
+    }
+
+    #[cfg(feature = "chrono")]
+    pub fn from_chrono(ty: DataRecordType, time: chrono::DateTime<chrono::Utc>) -> Option<Self> {
+        Self::from_system_time(ty, time.into())
+    }
+
+    #[cfg(feature = "time")]
+    pub fn to_offset_date_time(&self) -> Option<time::OffsetDateTime> {
+        self.to_system_time().map(Into::into)
+    }
+
+    #[cfg(feature = "time")]
+    pub fn from_offset_date_time(ty: DataRecordType, time: time::OffsetDateTime) -> Option<Self> {
+        Self::from_system_time(ty, time.into())
+    }
+}
+
+impl Message {
+    pub fn export_system_time(&self) -> SystemTime {
+        seconds_to_system_time(self.export_time)
+    }
+
+    #[cfg(feature = "chrono")]
+    pub fn export_chrono(&self) -> chrono::DateTime<chrono::Utc> {
+        self.export_system_time().into()
+    }
+
+    #[cfg(feature = "time")]
+    pub fn export_offset_date_time(&self) -> time::OffsetDateTime {
+        self.export_system_time().into()
+    }
+}
+
+impl MessageHeader {
+    pub fn export_system_time(&self) -> SystemTime {
+        seconds_to_system_time(self.export_time)
+    }
+
+    #[cfg(feature = "chrono")]
+    pub fn export_chrono(&self) -> chrono::DateTime<chrono::Utc> {
+        self.export_system_time().into()
+    }
+
+    #[cfg(feature = "time")]
+    pub fn export_offset_date_time(&self) -> time::OffsetDateTime {
+        self.export_system_time().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix_conversion() {
+        let epoch = NtpTimestamp::new(NtpTimestamp::UNIX_EPOCH_OFFSET, 0);
+        assert_eq!(epoch.unix_nanos(), 0);
+        assert_eq!(NtpTimestamp::from_unix_nanos(0), Some(epoch));
+
+        let half = NtpTimestamp::new(NtpTimestamp::UNIX_EPOCH_OFFSET + 1, 1 << 31);
+        assert_eq!(half.unix_nanos(), 1_500_000_000);
+        assert_eq!(half.unix_micros(), 1_500_000);
+        assert_eq!(NtpTimestamp::from_unix_micros(1_500_000), Some(half));
+
+        // before the Unix epoch
+        assert_eq!(
+            NtpTimestamp::from_unix_nanos(-500_000_000),
+            Some(NtpTimestamp::new(
+                NtpTimestamp::UNIX_EPOCH_OFFSET - 1,
+                1 << 31
+            ))
+        );
+
+        // out of range of the NTP eras
+        assert_eq!(NtpTimestamp::from_unix_nanos(i64::MAX), None);
+        assert_eq!(NtpTimestamp::from_unix_micros(-2_208_988_801_000_000), None);
+    }
+
+    #[test]
+    fn test_era() {
+        // 2036-02-07 06:28:16 UTC starts the next era, at 0 seconds
+        let era_start = (1 << 32) - i64::from(NtpTimestamp::UNIX_EPOCH_OFFSET);
+        let next_era = NtpTimestamp::new(0, 0);
+        assert_eq!(next_era.unix_nanos(), era_start * 1_000_000_000);
+        assert_eq!(
+            NtpTimestamp::from_unix_nanos(era_start * 1_000_000_000),
+            Some(next_era)
+        );
+        assert_eq!(
+            NtpTimestamp::from_unix_micros(era_start * 1_000_000 + 1).map(|t| t.unix_micros()),
+            Some(era_start * 1_000_000 + 1)
+        );
+        let last = NtpTimestamp::new(u32::MAX, 0);
+        assert_eq!(last.unix_micros(), (era_start - 1) * 1_000_000);
+        assert!(last < next_era);
+
+        // seconds with the top bit set are before 2036, from 1968
+        let first = NtpTimestamp::new(1 << 31, 0);
+        let start = (1 << 31) - i64::from(NtpTimestamp::UNIX_EPOCH_OFFSET);
+        assert_eq!(first.unix_nanos(), start * 1_000_000_000);
+        assert_eq!(
+            NtpTimestamp::from_unix_nanos(start * 1_000_000_000),
+            Some(first)
+        );
+        assert_eq!(
+            NtpTimestamp::from_unix_nanos(start * 1_000_000_000 - 1),
+            None
+        );
+
+        // and the next era ends in 2104
+        let end = era_start + (1 << 31);
+        assert_eq!(
+            NtpTimestamp::from_unix_nanos(end * 1_000_000_000 - 1_000_000_000),
+            Some(NtpTimestamp::new((1 << 31) - 1, 0))
+        );
+        assert_eq!(NtpTimestamp::from_unix_nanos(end * 1_000_000_000), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for nanos in [0, 1, 999_999_999, 1_700_000_000_123_456_789, -1] {
+            let timestamp = NtpTimestamp::from_unix_nanos(nanos).unwrap();
+            assert_eq!(timestamp.unix_nanos(), nanos);
+            assert_eq!(NtpTimestamp::from_bits(timestamp.to_bits()), timestamp);
+        }
+        for micros in [0, 1, 999_999, 1_700_000_000_123_456, -1] {
+            let timestamp = NtpTimestamp::from_unix_micros(micros).unwrap();
+            assert_eq!(timestamp.fraction & 0x7FF, 0);
+            assert_eq!(timestamp.unix_micros(), micros);
+        }
+    }
+
+    #[test]
+    fn test_system_time() {
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let cases = [
+            (
+                DataRecordType::DateTimeSeconds,
+                UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            ),
+            (
+                DataRecordType::DateTimeMilliseconds,
+                UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            ),
+            (
+                DataRecordType::DateTimeMicroseconds,
+                UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456),
+            ),
+            (DataRecordType::DateTimeNanoseconds, time),
+        ];
+        for (ty, expected) in cases {
+            let value = DataRecordValue::from_system_time(ty, time).unwrap();
+            assert_eq!(value.data_type(), ty);
+            assert_eq!(value.to_system_time(), Some(expected));
+            assert_eq!(
+                DataRecordValue::from_duration(ty, expected.duration_since(UNIX_EPOCH).unwrap()),
+                Some(value)
+            );
+        }
+
+        // before the Unix epoch, only NTP timestamps reach
+        let before = UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(
+            DataRecordValue::from_system_time(DataRecordType::DateTimeSeconds, before),
+            None
+        );
+        let value =
+            DataRecordValue::from_system_time(DataRecordType::DateTimeNanoseconds, before).unwrap();
+        assert_eq!(value.to_system_time(), Some(before));
+        assert_eq!(value.to_duration(), None);
+
+        assert_eq!(
+            DataRecordValue::from_system_time(DataRecordType::UnsignedInt, time),
+            None
+        );
+        assert_eq!(DataRecordValue::U64(0).to_system_time(), None);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_chrono() {
+        let value = DataRecordValue::DateTimeMilliseconds(1_700_000_000_123);
+        let time = value.to_chrono().unwrap();
+        assert_eq!(time.timestamp_millis(), 1_700_000_000_123);
+        assert_eq!(
+            DataRecordValue::from_chrono(DataRecordType::DateTimeMilliseconds, time),
+            Some(value)
+        );
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn test_time() {
+        let value = DataRecordValue::DateTimeSeconds(1_700_000_000);
+        let time = value.to_offset_date_time().unwrap();
+        assert_eq!(time.unix_timestamp(), 1_700_000_000);
+        assert_eq!(
+            DataRecordValue::from_offset_date_time(DataRecordType::DateTimeSeconds, time),
+            Some(value)
+        );
+    }
+}
@@ -1,41 +1,67 @@
-use std::num::TryFromIntError;
+use binrw::io::{Read, Seek, TakeSeekExt};
+use binrw::{BinRead, BinResult, Endian};
 
-use binrw::io::{Read, Seek, TakeSeekExt, Write};
-use binrw::{until_eof, BinRead, BinResult, BinWriterExt, Endian};
-
-#[derive(derive_more::From, derive_more::Error, derive_more::Display, Debug)]
-pub enum WritePositionError {
-    Io(binrw::io::Error),
-    TryFromInt(TryFromIntError),
-    BinRw(binrw::Error),
-}
-
-pub(crate) fn stream_position<S: Seek>(s: &mut S) -> Result<u16, WritePositionError> {
-    Ok(u16::try_from(s.stream_position()?)?)
-}
-
-/// Write the current position of the `writer` at `output_position`, minus `offset`
-/// This is used to get the length of a struct, via an empty field at the end
-pub(crate) fn write_position_at<W: Write + Seek>(
-    writer: &mut W,
-    output_position: u16,
-    offset: u16,
-) -> Result<(), WritePositionError> {
-    // TODO: avoid unwrap
-    let current_position = u16::try_from(writer.stream_position()?)?;
-    writer.seek(std::io::SeekFrom::Start(output_position.into()))?;
-    writer.write_be(&(current_position - offset))?;
-    Ok(())
-}
+use crate::error::{IpfixError, Limit};
 
+/// Read items from the next `limit` bytes until they run out, failing with
+/// `IpfixError::LimitExceeded` after more than `max_count` items, or items
+/// of more than `max_bytes` (see `Allocation`)
 pub(crate) fn until_limit<Reader, T, Arg, Ret>(
     limit: u64,
+    (count_limit, max_count): (Limit, usize),
+    max_bytes: usize,
 ) -> impl Fn(&mut Reader, Endian, Arg) -> BinResult<Ret> + Copy
 where
-    T: for<'a> BinRead<Args<'a> = Arg>,
+    T: for<'a> BinRead<Args<'a> = Arg> + Allocation,
     Reader: Read + Seek,
     Arg: Clone,
     Ret: FromIterator<T>,
 {
-    move |reader, endian, args| until_eof(&mut reader.take_seek(limit), endian, args)
+    move |reader, endian, args| {
+        let mut reader = reader.take_seek(limit);
+        let mut count = 0;
+        let mut bytes = 0;
+        std::iter::from_fn(|| {
+            let pos = match reader.stream_position() {
+                Ok(pos) => pos,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let item = match T::read_options(&mut reader, endian, args.clone()) {
+                Ok(item) => item,
+                Err(e) if e.is_eof() => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            count += 1;
+            bytes += item.allocated_bytes();
+            let exceeded = if count > max_count {
+                Some((count_limit, max_count))
+            } else if bytes > max_bytes {
+                Some((Limit::Bytes, max_bytes))
+            } else {
+                None
+            };
+            Some(match exceeded {
+                Some((limit, max)) => {
+                    Err(IpfixError::LimitExceeded { limit, max }.into_binrw_error(pos))
+                }
+                None => Ok(item),
+            })
+        })
+        .fuse()
+        .collect()
+    }
+}
+
+/// Estimated memory held by a decoded value, for `ParseLimits::max_bytes`
+pub(crate) trait Allocation {
+    fn allocated_bytes(&self) -> usize;
+}
+
+/// The current position of the reader, for `parse_with` on a `temp` field
+pub(crate) fn position<Reader: Read + Seek>(
+    reader: &mut Reader,
+    _endian: Endian,
+    _args: (),
+) -> BinResult<u64> {
+    Ok(reader.stream_position()?)
 }
@@ -0,0 +1,111 @@
+//! Streaming of records to a visitor, without building a `Message`
+
+use std::rc::Rc;
+
+use crate::{
+    borrowed::{DataRecordRef, MessageRef},
+    error::{Error, IpfixError, Limit},
+    information_elements::Formatter,
+    parser::{MessageHeader, ParseOptions, Records},
+    template_store::{Template, TemplateStore},
+};
+
+/// Hooks called by `visit_ipfix_message` as it walks a message. All hooks
+/// do nothing by default
+pub trait RecordVisitor {
+    fn on_message_header(&mut self, _header: &MessageHeader) {}
+
+    /// Called for each template or options template, after it has been
+    /// inserted into the template store
+    fn on_template(&mut self, _template_id: u16, _template: &Template) {}
+
+    /// Called for each record of a data set. The record borrows from the
+    /// message buffer, and its fields are decoded as they are iterated,
+    /// with the options of the visit
+    fn on_data_record(&mut self, _template_id: u16, _record: DataRecordRef<'_, '_>) {}
+
+    /// Called after each set, including sets buffered by the template store
+    fn on_set_end(&mut self, _set_id: u16) {}
+}
+
+/// Walk a message, updating `templates` and calling `visitor` for its
+/// header, templates and data records
+pub fn visit_ipfix_message<T: AsRef<[u8]>>(
+    buf: &T,
+    templates: TemplateStore,
+    formatter: Rc<Formatter>,
+    visitor: &mut impl RecordVisitor,
+) -> Result<(), Error> {
+    visit_ipfix_message_with_options(buf, templates, formatter, ParseOptions::default(), visitor)
+}
+
+/// `visit_ipfix_message`, with the options and limits of `options`
+pub fn visit_ipfix_message_with_options<T: AsRef<[u8]>>(
+    buf: &T,
+    templates: TemplateStore,
+    formatter: Rc<Formatter>,
+    options: ParseOptions,
+    visitor: &mut impl RecordVisitor,
+) -> Result<(), Error> {
+    let limits = options.limits;
+    let msg = MessageRef::new(buf.as_ref())?;
+    visitor.on_message_header(&msg.header);
+
+    for (index, set) in msg.sets().enumerate() {
+        let set = set?;
+        if index == limits.max_sets {
+            let limit = IpfixError::LimitExceeded {
+                limit: Limit::Sets,
+                max: limits.max_sets,
+            };
+            return Err(Error::new(limit, 0).in_set(set.set_id, set.offset as u64));
+        }
+        match set.set_id {
+            2 | 3 => {
+                let template_ids: Vec<u16> = match set
+                    .to_set_with_options(templates.clone(), formatter.clone(), options)?
+                    .records
+                {
+                    Records::Template(records) => {
+                        records.iter().map(|record| record.template_id).collect()
+                    }
+                    Records::OptionsTemplate(records) => {
+                        records.iter().map(|record| record.template_id).collect()
+                    }
+                    _ => Vec::new(),
+                };
+                for template_id in template_ids {
+                    if let Some(template) = templates.get_template(template_id) {
+                        visitor.on_template(template_id, &template);
+                    }
+                }
+            }
+            set_id if set.is_data_set() => match templates.get_template(set_id) {
+                Some(template) => {
+                    for (index, record) in set
+                        .data_records_with_options(&template, options)
+                        .enumerate()
+                    {
+                        if index == limits.max_records {
+                            let limit = IpfixError::LimitExceeded {
+                                limit: Limit::Records,
+                                max: limits.max_records,
+                            };
+                            return Err(Error::new(limit, 0).in_set(set_id, set.offset as u64));
+                        }
+                        visitor.on_data_record(set_id, record?);
+                    }
+                }
+                None => templates
+                    .buffer_data_set(set_id, set.content(), &formatter, options)
+                    .map_err(|e| Error::new(e, 0).in_set(set_id, set.offset as u64))?,
+            },
+            set_id => {
+                return Err(Error::new(IpfixError::ReservedSetId(set_id), 0)
+                    .in_set(set_id, set.offset as u64))
+            }
+        }
+        visitor.on_set_end(set.set_id);
+    }
+    Ok(())
+}
@@ -1,14 +1,46 @@
 use std::cell::RefCell;
+use std::io::Cursor;
 use std::net::Ipv4Addr;
 use std::rc::Rc;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, UNIX_EPOCH};
 
 use ahash::{HashMap, HashMapExt};
+use binrw::{BinRead, BinWrite};
 
+use ipfixrw::borrowed::{DataRecordValueRef, MessageRef};
+use ipfixrw::data_record;
+use ipfixrw::error::{Error, Limit};
 use ipfixrw::information_elements::get_default_formatter;
-use ipfixrw::parse_ipfix_message;
-use ipfixrw::parser::{DataRecord, DataRecordKey, DataRecordType, DataRecordValue};
-use ipfixrw::template_store::Template;
+use ipfixrw::parser::{
+    BasicList, DataRecord, DataRecordKey, DataRecordType, DataRecordValue, FieldSpecifier,
+    IpfixError, ListSemantic, Message, ParseLimits, ParseOptions, Records, Set, StringPolicy,
+    SubTemplateList, SubTemplateMultiList, SubTemplateMultiListEntry, TemplateWithdrawalRecord,
+    Validation, WriteOptions,
+};
+use ipfixrw::pending::{BufferedTemplateStore, PendingLimits};
+use ipfixrw::resolver::{FlowTimes, ResolutionMethod, ResolvedTime, TimestampResolver};
+use ipfixrw::template_store::{ScopedTemplateStore, Template, TemplateStore};
+use ipfixrw::visitor::{visit_ipfix_message, visit_ipfix_message_with_options, RecordVisitor};
+use ipfixrw::{
+    parse_ipfix_message, parse_ipfix_message_lenient, parse_ipfix_message_lenient_with_options,
+    parse_ipfix_message_scoped, parse_ipfix_message_scoped_with_options,
+    parse_ipfix_message_with_options, parse_ipfix_messages, parse_ipfix_messages_scoped,
+};
+
+/// A message holding `sets`, given in hex
+fn message(sets: &str) -> Vec<u8> {
+    message_in_domain(0, sets)
+}
+
+fn message_in_domain(observation_domain_id: u32, sets: &str) -> Vec<u8> {
+    let sets = hex::decode(sets).unwrap();
+    let mut bytes = hex::decode("000A0000000000000000000000000000").unwrap();
+    bytes[2..4].copy_from_slice(&(16 + sets.len() as u16).to_be_bytes());
+    bytes[12..16].copy_from_slice(&observation_domain_id.to_be_bytes());
+    bytes.extend(sets);
+    bytes
+}
 
 // shall not cause infinite loop
 #[test]
@@ -22,6 +54,27 @@ fn looper_01() {
     assert!(m.is_err());
 }
 
+// shall not cause infinite loop
+#[test]
+fn looper_borrowed() {
+    // 256: paddingOctets with length 0, so its records take no bytes
+    let template_set = "0002000C0100000100D20000";
+    let bytes = message(&format!("{template_set}0100000500"));
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+    let msg_ref = MessageRef::new(&bytes).unwrap();
+    let mut sets = msg_ref.sets();
+    sets.next()
+        .unwrap()
+        .unwrap()
+        .to_set(templates.clone(), formatter)
+        .unwrap();
+    let set = sets.next().unwrap().unwrap();
+    let template = templates.borrow()[&256].clone();
+    assert_eq!(set.data_records(&template).count(), 0);
+}
+
 #[test]
 fn test_parse() {
     // contains templates 500, 999, 501
@@ -49,27 +102,20 @@ fn test_parse() {
     let d0 = datarecords[0];
     assert_eq!(d0.values.len(), 11);
     assert_eq!(
-        d0.values
-            .get(&DataRecordKey::Str("sourceIPv4Address"))
-            .unwrap(),
+        d0.get(&DataRecordKey::Str("sourceIPv4Address")).unwrap(),
         &DataRecordValue::Ipv4Addr(Ipv4Addr::new(172, 19, 219, 50))
     );
     assert_eq!(
-        d0.values
-            .get(&DataRecordKey::Str("flowEndMilliseconds"))
-            .unwrap(),
+        d0.get(&DataRecordKey::Str("flowEndMilliseconds")).unwrap(),
         &DataRecordValue::DateTimeMilliseconds(1479840960376)
     );
     assert_eq!(
-        d0.values
-            .get(&DataRecordKey::Str("destinationTransportPort"))
+        d0.get(&DataRecordKey::Str("destinationTransportPort"))
             .unwrap(),
         &DataRecordValue::U16(53)
     );
     assert_eq!(
-        d0.values
-            .get(&DataRecordKey::Str("protocolIdentifier"))
-            .unwrap(),
+        d0.get(&DataRecordKey::Str("protocolIdentifier")).unwrap(),
         &DataRecordValue::U8(17)
     );
 }
@@ -91,10 +137,7 @@ fn test_parse_template_enterprise_fields() {
     let enterprise_fields = templates
         .borrow()
         .values()
-        .flat_map(|t| match t {
-            Template::Template(field_specifiers) => field_specifiers,
-            Template::OptionsTemplate(field_specifiers) => field_specifiers,
-        })
+        .flat_map(|t| t.field_specifiers())
         .filter(|fs| fs.enterprise_number.is_some())
         .count();
 
@@ -171,12 +214,33 @@ fn test_parse_data_variable_fields() {
     let record = records[0];
     assert_eq!(record.values.len(), 41);
 
-    if let DataRecordValue::String(query) =
-        record.values.get(&DataRecordKey::Str("DNS_QUERY")).unwrap()
-    {
+    if let DataRecordValue::String(query) = record.get(&DataRecordKey::Str("DNS_QUERY")).unwrap() {
         assert_eq!(query, "asimov.vortex.data.trafficmanager.net");
     }
 
+    // lazily decode only the requested fields
+    let template = templates.borrow()[&259].clone();
+    let msg_ref = MessageRef::new(d1).unwrap();
+    let set = msg_ref.sets().next().unwrap().unwrap();
+    assert_eq!(set.set_id, 259);
+    let lazy = set
+        .data_records(&template)
+        .next()
+        .unwrap()
+        .unwrap()
+        .to_lazy();
+    assert_eq!(lazy.len(), 41);
+    assert_eq!(
+        lazy.get(&DataRecordKey::Str("DNS_QUERY")).unwrap().unwrap(),
+        DataRecordValueRef::String("asimov.vortex.data.trafficmanager.net")
+    );
+    for (index, (key, value)) in record.iter().enumerate() {
+        assert_eq!(&lazy.field_specifier(index).unwrap().name, key);
+        assert_eq!(&lazy.get_index(index).unwrap().unwrap().to_value(), value);
+    }
+    assert!(lazy.get_index(41).is_none());
+    assert!(lazy.get(&DataRecordKey::Str("HTTP_SITE")).is_none());
+
     // http
     let http = parse_ipfix_message(d2, templates, formatter.clone()).unwrap();
     let records: Vec<&DataRecord> = http.iter_data_records().collect();
@@ -184,13 +248,1203 @@ fn test_parse_data_variable_fields() {
     let record = records[0];
     assert_eq!(record.values.len(), 42);
 
-    if let DataRecordValue::String(site) =
-        record.values.get(&DataRecordKey::Str("HTTP_SITE")).unwrap()
-    {
+    if let DataRecordValue::String(site) = record.get(&DataRecordKey::Str("HTTP_SITE")).unwrap() {
         assert_eq!(site, "example.com");
     }
 }
 
+#[test]
+fn test_parse_duplicate_fields() {
+    #[rustfmt::skip]
+    let bytes = hex::decode(concat!(
+        "000A002E000000000000000000000000",
+        // template 256: interfaceName, interfaceName
+        "00020010", "01000002", "0052FFFF", "0052FFFF",
+        "0100000E", "046574683004", "65746831",
+    ))
+    .unwrap();
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+
+    let msg = parse_ipfix_message(&bytes, templates.clone(), formatter.clone()).unwrap();
+    let record = msg.iter_data_records().next().unwrap();
+    assert_eq!(record.len(), 2);
+    let key = DataRecordKey::Str("interfaceName");
+    assert_eq!(
+        record.get_all(&key).collect::<Vec<_>>(),
+        [
+            &DataRecordValue::String("eth0".into()),
+            &DataRecordValue::String("eth1".into())
+        ]
+    );
+    assert_eq!(
+        record.get(&key),
+        Some(&DataRecordValue::String("eth0".into()))
+    );
+
+    let mut writer = Cursor::new(Vec::new());
+    msg.write_args(&mut writer, (templates, formatter, 1))
+        .unwrap();
+    assert_eq!(writer.into_inner(), bytes);
+}
+
+#[test]
+fn test_parse_basic_list() {
+    #[rustfmt::skip]
+    let bytes = hex::decode(concat!(
+        "000A003D000000000000000000000000",
+        // template 256: bgpSourceCommunityList, basicList
+        "00020010", "01000002", "01E4FFFF", "0123FFFF",
+        "0100001D",
+        // allOf bgpCommunity (4 bytes)
+        "0D", "03", "01E30004", "00010002", "00030004",
+        // ordered interfaceName (variable length)
+        "0A", "04", "0052FFFF", "0161", "026263",
+    ))
+    .unwrap();
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+
+    let msg = parse_ipfix_message(&bytes, templates.clone(), formatter.clone()).unwrap();
+    let record = msg.iter_data_records().next().unwrap();
+    assert_eq!(
+        record.get(&DataRecordKey::Str("bgpSourceCommunityList")),
+        Some(&DataRecordValue::BasicList(BasicList {
+            semantic: ListSemantic::AllOf,
+            field_specifier: FieldSpecifier::new(None, 483, 4),
+            values: vec![
+                DataRecordValue::U32(0x0001_0002).into(),
+                DataRecordValue::U32(0x0003_0004).into()
+            ],
+        }))
+    );
+    assert_eq!(
+        record.get(&DataRecordKey::Str("basicList")),
+        Some(&DataRecordValue::BasicList(BasicList {
+            semantic: ListSemantic::Ordered,
+            field_specifier: FieldSpecifier::new(None, 82, u16::MAX),
+            values: vec![
+                DataRecordValue::String("a".into()).into(),
+                DataRecordValue::String("bc".into()).into()
+            ],
+        }))
+    );
+
+    let mut writer = Cursor::new(Vec::new());
+    msg.write_args(&mut writer, (templates.clone(), formatter.clone(), 1))
+        .unwrap();
+    assert_eq!(writer.into_inner(), bytes);
+
+    // semantics not registered with IANA are kept
+    let mut unknown_semantic = bytes.clone();
+    unknown_semantic[51] = 0x08;
+    let msg = parse_ipfix_message(&unknown_semantic, templates.clone(), formatter.clone()).unwrap();
+    let record = msg.iter_data_records().next().unwrap();
+    let Some(DataRecordValue::BasicList(list)) = record.get(&DataRecordKey::Str("basicList"))
+    else {
+        panic!("{record:?}");
+    };
+    assert_eq!(list.semantic, ListSemantic::Unknown(0x08));
+    assert_eq!(
+        msg.to_bytes(templates, formatter, 1).unwrap(),
+        unknown_semantic
+    );
+}
+
+#[test]
+fn test_parse_sub_template_lists() {
+    // templates 256, 257 and 258, with a data set for 258 nesting 256 and 257
+    let bytes = include_bytes!("../resources/tests/lists_samp.bin");
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+
+    let msg = parse_ipfix_message(bytes, templates.clone(), formatter.clone()).unwrap();
+    let record = msg.iter_data_records().next().unwrap();
+
+    let flow = |addr: [u8; 4], port| {
+        data_record! {
+            "sourceIPv4Address": Ipv4Addr(addr.into()),
+            "destinationTransportPort": U16(port),
+        }
+    };
+    assert_eq!(
+        record.get(&DataRecordKey::Str("subTemplateList")),
+        Some(&DataRecordValue::SubTemplateList(SubTemplateList {
+            semantic: ListSemantic::AllOf,
+            template_id: 256,
+            data: vec![flow([10, 0, 0, 1], 80), flow([10, 0, 0, 2], 443)],
+        }))
+    );
+    assert_eq!(
+        record.get(&DataRecordKey::Str("subTemplateMultiList")),
+        Some(&DataRecordValue::SubTemplateMultiList(
+            SubTemplateMultiList {
+                semantic: ListSemantic::AllOf,
+                entries: vec![
+                    SubTemplateMultiListEntry {
+                        template_id: 256,
+                        data: vec![flow([192, 168, 0, 1], 53)],
+                    },
+                    SubTemplateMultiListEntry {
+                        template_id: 257,
+                        data: vec![
+                            data_record! { "interfaceName": String("eth0".into()) },
+                            data_record! { "interfaceName": String("eth1".into()) },
+                        ],
+                    },
+                ],
+            }
+        ))
+    );
+
+    // nested templates are looked up when decoding
+    templates.borrow_mut().remove(&256);
+    let err = DataRecord::read_be_args(
+        &mut Cursor::new(&bytes[60..]),
+        (258, templates, formatter, ParseOptions::default()),
+    )
+    .unwrap_err();
+    let err = Error::from(err);
+    assert!(matches!(err.kind, IpfixError::MissingTemplate(256)));
+    assert_eq!(err.template_id, Some(258));
+    assert_eq!(err.field_index, Some(0));
+    assert_eq!(err.information_element, Some((0, 292)));
+}
+
+#[test]
+fn test_parse_borrowed() {
+    let temp = include_bytes!("../resources/tests/parse_temp_2.bin");
+    let data = include_bytes!("../resources/tests/http_samp.bin");
+    let lists = include_bytes!("../resources/tests/lists_samp.bin");
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+
+    let temp_ref = MessageRef::new(temp).unwrap();
+    for set in temp_ref.sets() {
+        set.unwrap()
+            .to_set(templates.clone(), formatter.clone())
+            .unwrap();
+    }
+
+    // the borrowed view decodes the same values as the owned message
+    for bytes in [&data[..], &lists[..]] {
+        let msg_ref = MessageRef::new(bytes).unwrap();
+        let msg = msg_ref
+            .to_message(templates.clone(), formatter.clone())
+            .unwrap();
+        let msg_ref = MessageRef::new(bytes).unwrap();
+        assert_eq!(msg_ref.header.sequence_number, msg.sequence_number);
+
+        let mut owned_records = msg.iter_data_records();
+        for set in msg_ref.sets() {
+            let set = set.unwrap();
+            if !set.is_data_set() {
+                continue;
+            }
+            let template = templates.borrow()[&set.set_id].clone();
+            for record in set.data_records(&template) {
+                let record = record.unwrap();
+                let owned = owned_records.next().unwrap();
+                let values: Vec<_> = record.fields().map(|(_, v)| v.unwrap()).collect();
+                assert_eq!(values.len(), owned.len());
+                for ((key, owned_value), value) in owned.iter().zip(&values) {
+                    match value {
+                        DataRecordValueRef::List(_) => {}
+                        value => assert_eq!(&value.to_value(), owned_value, "{key:?}"),
+                    }
+                }
+                let store: ipfixrw::template_store::TemplateStore = templates.clone();
+                assert_eq!(&record.to_data_record(&store, &formatter).unwrap(), owned);
+            }
+        }
+        assert!(owned_records.next().is_none());
+    }
+
+    // list contents are slices of the input
+    let msg_ref = MessageRef::new(lists).unwrap();
+    let set = msg_ref.sets().nth(1).unwrap().unwrap();
+    let template = templates.borrow()[&258].clone();
+    let record = set.data_records(&template).next().unwrap().unwrap();
+    let Some(Ok(DataRecordValueRef::List(content))) =
+        record.get(&DataRecordKey::Str("subTemplateList"))
+    else {
+        panic!("expected a list");
+    };
+    assert!(lists.as_ptr_range().contains(&content.as_ptr()));
+
+    // a message longer than the buffer is reported
+    let mut truncated = lists.to_vec();
+    truncated.truncate(100);
+    assert!(matches!(
+        MessageRef::new(&truncated).unwrap_err().kind,
+        IpfixError::TruncatedMessage {
+            length: 112,
+            available: 100
+        }
+    ));
+
+    // as is an overrunning set length
+    truncated[2..4].copy_from_slice(&100u16.to_be_bytes());
+    let msg_ref = MessageRef::new(&truncated).unwrap();
+    let sets: Vec<_> = msg_ref.sets().collect();
+    assert!(sets[0].is_ok());
+    assert!(sets[1].is_err());
+}
+
+#[test]
+fn test_visitor() {
+    #[derive(Default)]
+    struct Visitor {
+        events: Vec<String>,
+        records: Vec<DataRecord>,
+    }
+
+    impl RecordVisitor for Visitor {
+        fn on_message_header(&mut self, header: &ipfixrw::parser::MessageHeader) {
+            self.events
+                .push(format!("header {}", header.sequence_number));
+        }
+        fn on_template(&mut self, template_id: u16, template: &Template) {
+            self.events.push(format!(
+                "template {template_id} {}",
+                template.field_specifiers().len()
+            ));
+        }
+        fn on_data_record(&mut self, template_id: u16, record: ipfixrw::borrowed::DataRecordRef) {
+            self.events.push(format!("record {template_id}"));
+            self.records.push(
+                record
+                    .fields()
+                    .map(|(field_spec, value)| (field_spec.name.clone(), value.unwrap().to_value()))
+                    .collect(),
+            );
+        }
+        fn on_set_end(&mut self, set_id: u16) {
+            self.events.push(format!("end {set_id}"));
+        }
+    }
+
+    // contains templates 500, 999, 501
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    // contains data sets for templates 999, 500, 999
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+
+    // data before its template is an error for a plain template store
+    let mut visitor = Visitor::default();
+    let err = visit_ipfix_message(
+        data_bytes,
+        templates.clone(),
+        formatter.clone(),
+        &mut visitor,
+    )
+    .unwrap_err();
+    assert!(matches!(err.kind, IpfixError::MissingTemplate(999)));
+    assert_eq!(err.set_id, Some(999));
+    assert_eq!(err.offset, 16);
+
+    let mut visitor = Visitor::default();
+    visit_ipfix_message(
+        template_bytes,
+        templates.clone(),
+        formatter.clone(),
+        &mut visitor,
+    )
+    .unwrap();
+    visit_ipfix_message(
+        data_bytes,
+        templates.clone(),
+        formatter.clone(),
+        &mut visitor,
+    )
+    .unwrap();
+
+    let msg = parse_ipfix_message(data_bytes, templates, formatter).unwrap();
+    let expected: Vec<DataRecord> = msg.iter_data_records().cloned().collect();
+    assert_eq!(visitor.records, expected);
+    assert_eq!(
+        visitor.events[..6],
+        [
+            "header 150168211",
+            "template 500 27",
+            "template 999 11",
+            "template 501 27",
+            "end 2",
+            "header 150169160",
+        ]
+    );
+    assert_eq!(visitor.events.iter().filter(|e| *e == "end 999").count(), 2);
+    assert_eq!(visitor.events.last().unwrap(), "end 999");
+}
+
+#[test]
+fn test_visitor_limits() {
+    #[derive(Default)]
+    struct Counter {
+        records: usize,
+    }
+
+    impl RecordVisitor for Counter {
+        fn on_data_record(&mut self, _template_id: u16, _record: ipfixrw::borrowed::DataRecordRef) {
+            self.records += 1;
+        }
+    }
+
+    let formatter = Rc::new(get_default_formatter());
+    let visit = |bytes: &[u8], limits: ParseLimits| {
+        let templates = Rc::new(RefCell::new(HashMap::new()));
+        let options = ParseOptions {
+            limits,
+            ..Default::default()
+        };
+        let mut counter = Counter::default();
+        visit_ipfix_message_with_options(
+            &bytes,
+            templates,
+            formatter.clone(),
+            options,
+            &mut counter,
+        )
+        .map(|_| counter.records)
+    };
+
+    // 256: paddingOctets with length 0, so its records take no bytes
+    let empty_records = message("0002000C0100000100D200000100000500");
+    assert_eq!(visit(&empty_records, ParseLimits::default()).unwrap(), 0);
+
+    // 256: sourceIPv4Address, with 3 records
+    let records = message("0002000C0100000100080004010000100A0000010A0000020A000003");
+    assert_eq!(visit(&records, ParseLimits::default()).unwrap(), 3);
+    let limits = ParseLimits {
+        max_records: 2,
+        ..Default::default()
+    };
+    let error = visit(&records, limits).unwrap_err();
+    assert!(matches!(
+        error.kind,
+        IpfixError::LimitExceeded {
+            limit: Limit::Records,
+            max: 2
+        }
+    ));
+    assert_eq!(error.set_id, Some(256));
+    let limits = ParseLimits {
+        max_templates: 0,
+        ..Default::default()
+    };
+    let error = visit(&records, limits).unwrap_err();
+    assert!(matches!(
+        error.kind,
+        IpfixError::LimitExceeded {
+            limit: Limit::Templates,
+            max: 0
+        }
+    ));
+
+    // malformed sets fail as they do in an owned parse
+    // 257: interfaceName (variable length), with 3 bytes of a 6 byte name
+    let truncated = message("0002000C010100010052FFFF0101000806657468");
+    let error = visit(&truncated, ParseLimits::default()).unwrap_err();
+    assert!(matches!(error.kind, IpfixError::TruncatedRecord));
+    assert_eq!(error.set_id, Some(257));
+    let trailing = message("0002000C0100000100080004010000100A0000010A0000020A0000030000");
+    let error = visit(&trailing, ParseLimits::default()).unwrap_err();
+    assert!(matches!(error.kind, IpfixError::BadSetLength(0)));
+}
+
+#[test]
+fn test_template_withdrawal() {
+    // templates 256, 257 and 258, with a data set for 258 nesting 256 and 257
+    let bytes = include_bytes!("../resources/tests/lists_samp.bin");
+    let mut data_bytes = hex::decode("000A0048000000000000000000000000").unwrap();
+    data_bytes.extend_from_slice(&bytes[56..]);
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+
+    parse_ipfix_message(bytes, templates.clone(), formatter.clone()).unwrap();
+    templates.borrow_mut().insert(
+        300,
+        Template::OptionsTemplate {
+            scope_field_count: 1,
+            field_specifiers: vec![],
+        },
+    );
+    assert_eq!(templates.borrow().len(), 4);
+
+    let withdraw_257 = hex::decode("000A00180000000000000000000000000002000801010000").unwrap();
+    let msg = parse_ipfix_message(&withdraw_257, templates.clone(), formatter.clone()).unwrap();
+    assert_eq!(
+        msg.sets,
+        [Set {
+            records: Records::TemplateWithdrawal {
+                set_id: 2,
+                withdrawals: vec![TemplateWithdrawalRecord { template_id: 257 }],
+            },
+            padding: Some(vec![]),
+        }]
+    );
+    assert!(!templates.borrow().contains_key(&257));
+    assert_eq!(templates.borrow().len(), 3);
+
+    // data for a withdrawn template can no longer be decoded
+    assert!(parse_ipfix_message(&data_bytes, templates.clone(), formatter.clone()).is_err());
+
+    let mut writer = Cursor::new(Vec::new());
+    msg.write_args(&mut writer, (templates.clone(), formatter.clone(), 1))
+        .unwrap();
+    assert_eq!(writer.into_inner(), withdraw_257);
+
+    // withdrawing all templates keeps options templates
+    let withdraw_all = hex::decode("000A00180000000000000000000000000002000800020000").unwrap();
+    let msg = parse_ipfix_message(&withdraw_all, templates.clone(), formatter).unwrap();
+    assert!(msg
+        .iter_template_withdrawal_records()
+        .all(TemplateWithdrawalRecord::withdraws_all));
+    assert_eq!(templates.borrow().len(), 1);
+    assert!(templates.borrow().contains_key(&300));
+}
+
+#[test]
+fn test_parse_errors() {
+    // 256: interfaceName (variable length)
+    // 257: sourceIPv4Address with length 3
+    // 258: octetDeltaCount with length 3
+    let template_set = "0002001C\
+        010000010052FFFF\
+        0101000100080003\
+        0102000100010003";
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+    let parse = |bytes: &[u8]| {
+        parse_ipfix_message(&bytes, templates.clone(), formatter.clone()).unwrap_err()
+    };
+    parse_ipfix_message(&message(template_set), templates.clone(), formatter.clone()).unwrap();
+
+    let err = parse(&hex::decode("000A0010").unwrap());
+    assert!(matches!(err.kind, IpfixError::TruncatedHeader(4)));
+
+    let err = parse(&hex::decode("00090010000000000000000000000000").unwrap());
+    assert!(matches!(err.kind, IpfixError::BadVersion(9)));
+
+    let err = parse(&message("0005000800000000"));
+    assert!(matches!(err.kind, IpfixError::ReservedSetId(5)));
+    assert_eq!((err.set_id, err.offset), (Some(5), 16));
+
+    let err = parse(&message("00020004"));
+    assert!(matches!(err.kind, IpfixError::BadSetLength(4)));
+
+    let err = parse(&message("0002000C0010000100080004"));
+    assert!(matches!(err.kind, IpfixError::ReservedTemplateId(16)));
+    assert_eq!(err.set_id, Some(2));
+
+    let err = parse(&message("0100000802FFFE00"));
+    assert!(matches!(err.kind, IpfixError::InvalidUtf8(_)));
+    assert_eq!(err.offset, 20);
+    assert_eq!(err.set_id, Some(256));
+    assert_eq!(err.template_id, Some(256));
+    assert_eq!(err.field_index, Some(0));
+    assert_eq!(err.information_element, Some((0, 82)));
+
+    let err = parse(&message("010100080A000001"));
+    assert!(matches!(
+        err.kind,
+        IpfixError::InvalidFieldSpecLength {
+            ty: DataRecordType::Ipv4Addr,
+            length: 3
+        }
+    ));
+    assert_eq!(err.information_element, Some((0, 8)));
+
+    let msg = ipfixrw::parser::Message {
+        export_time: 0,
+        sequence_number: 0,
+        observation_domain_id: 0,
+        sets: vec![Set {
+            records: Records::Data {
+                set_id: 258,
+                data: vec![data_record! { "octetDeltaCount": U24(1 << 24) }],
+            },
+            padding: None,
+        }],
+    };
+    let err = msg.to_bytes(templates, formatter, 4).unwrap_err();
+    assert!(matches!(
+        err.kind,
+        IpfixError::ValueOutOfRange {
+            value: 0x100_0000,
+            length: 3
+        }
+    ));
+    assert_eq!(
+        (err.set_id, err.field_index, err.offset),
+        (Some(258), Some(0), 20)
+    );
+}
+
+#[test]
+fn test_string_policy() {
+    // 256: interfaceName (variable length), 257: interfaceName (length 8)
+    let template_bytes =
+        hex::decode("000A002400000000000000000000000000020014010000010052FFFF0101000100520008")
+            .unwrap();
+    let invalid_bytes = hex::decode("000A00180000000000000000000000000100000802FFFE00").unwrap();
+    let padded_bytes =
+        hex::decode("000A001C0000000000000000000000000101000C6574683000000000").unwrap();
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+    parse_ipfix_message(&template_bytes, templates.clone(), formatter.clone()).unwrap();
+
+    let interface_name = |bytes: &[u8], options: ParseOptions| {
+        let key = DataRecordKey::Str("interfaceName");
+        let msg =
+            parse_ipfix_message_with_options(&bytes, templates.clone(), formatter.clone(), options)
+                .unwrap();
+        let value = msg
+            .iter_data_records()
+            .next()
+            .unwrap()
+            .get(&key)
+            .unwrap()
+            .clone();
+
+        // the borrowed view decodes with the same options
+        let msg_ref = MessageRef::new(bytes).unwrap();
+        let set = msg_ref.sets().next().unwrap().unwrap();
+        let template = templates.borrow()[&set.set_id].clone();
+        let mut records = set.data_records_with_options(&template, options);
+        let record = records.next().unwrap().unwrap();
+        assert_eq!(record.get(&key).unwrap().unwrap().to_value(), value);
+        value
+    };
+
+    assert!(parse_ipfix_message(&invalid_bytes, templates.clone(), formatter.clone()).is_err());
+    let lossy = ParseOptions {
+        strings: StringPolicy::Lossy,
+        ..Default::default()
+    };
+    assert_eq!(
+        interface_name(&invalid_bytes, lossy),
+        DataRecordValue::String("\u{FFFD}\u{FFFD}".into())
+    );
+    let raw = ParseOptions {
+        strings: StringPolicy::Bytes,
+        ..Default::default()
+    };
+    assert_eq!(
+        interface_name(&invalid_bytes, raw),
+        DataRecordValue::Bytes(vec![0xFF, 0xFE])
+    );
+
+    assert_eq!(
+        interface_name(&padded_bytes, ParseOptions::default()),
+        DataRecordValue::String("eth0\0\0\0\0".into())
+    );
+    let trim = ParseOptions {
+        trim_trailing_nul: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        interface_name(&padded_bytes, trim),
+        DataRecordValue::String("eth0".into())
+    );
+
+    // trimmed strings are padded again when written
+    let msg =
+        parse_ipfix_message_with_options(&padded_bytes, templates.clone(), formatter.clone(), trim)
+            .unwrap();
+    assert_eq!(
+        msg.to_bytes(templates.clone(), formatter.clone(), 1)
+            .unwrap(),
+        padded_bytes
+    );
+
+    // strings kept as bytes are not trimmed, so are written back whole
+    let invalid_padded_bytes =
+        hex::decode("000A001C0000000000000000000000000101000CFFFE000000000000").unwrap();
+    let trim_raw = ParseOptions {
+        trim_trailing_nul: true,
+        ..raw
+    };
+    assert_eq!(
+        interface_name(&invalid_padded_bytes, trim_raw),
+        DataRecordValue::Bytes(vec![0xFF, 0xFE, 0, 0, 0, 0, 0, 0])
+    );
+    let msg = parse_ipfix_message_with_options(
+        &invalid_padded_bytes,
+        templates.clone(),
+        formatter.clone(),
+        trim_raw,
+    )
+    .unwrap();
+    assert_eq!(
+        msg.to_bytes(templates, formatter, 1).unwrap(),
+        invalid_padded_bytes
+    );
+}
+
+#[test]
+fn test_raw_fallback() {
+    // 256: sourceIPv4Address with length 8, dataRecordsReliability with length 2
+    let template_bytes =
+        hex::decode("000A002000000000000000000000000000020010010000020008000801140002").unwrap();
+    let data_bytes =
+        hex::decode("000A001E0000000000000000000000000100000E0A0000010A0000020001").unwrap();
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+    parse_ipfix_message(&template_bytes, templates.clone(), formatter.clone()).unwrap();
+
+    let err = parse_ipfix_message(&data_bytes, templates.clone(), formatter.clone()).unwrap_err();
+    assert!(matches!(
+        err.kind,
+        IpfixError::InvalidFieldSpecLength { length: 8, .. }
+    ));
+
+    let options = ParseOptions {
+        raw_fallback: true,
+        ..Default::default()
+    };
+    let msg = parse_ipfix_message_with_options(
+        &data_bytes,
+        templates.clone(),
+        formatter.clone(),
+        options,
+    )
+    .unwrap();
+    let record = msg.iter_data_records().next().unwrap();
+    assert!(record.has_raw_values());
+    assert_eq!(
+        record.get(&DataRecordKey::Str("sourceIPv4Address")),
+        Some(&DataRecordValue::Raw {
+            ty: DataRecordType::Ipv4Addr,
+            bytes: vec![10, 0, 0, 1, 10, 0, 0, 2],
+        })
+    );
+    assert_eq!(
+        record.get(&DataRecordKey::Str("dataRecordsReliability")),
+        Some(&DataRecordValue::Raw {
+            ty: DataRecordType::Bool,
+            bytes: vec![0, 1],
+        })
+    );
+
+    // written back as it was read
+    assert_eq!(msg.to_bytes(templates, formatter, 1).unwrap(), data_bytes);
+}
+
+#[test]
+fn test_parse_lenient() {
+    // templates 256, 257 and 258, with a data set for 258 nesting 256 and 257
+    let bytes = include_bytes!("../resources/tests/lists_samp.bin");
+    // data set for unknown template 512, followed by the good sets
+    let mut lenient_bytes = hex::decode("000A0078000000000000000000000000").unwrap();
+    lenient_bytes.extend_from_slice(&hex::decode("0200000800000000").unwrap());
+    lenient_bytes.extend_from_slice(&bytes[16..]);
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+
+    assert!(parse_ipfix_message(&lenient_bytes, templates.clone(), formatter.clone()).is_err());
+
+    let (msg, errors) =
+        parse_ipfix_message_lenient(&lenient_bytes, templates.clone(), formatter.clone()).unwrap();
+    assert_eq!(msg.sets.len(), 2);
+    assert_eq!(msg.iter_data_records().count(), 1);
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0].kind, IpfixError::MissingTemplate(512)));
+    assert_eq!(errors[0].set_id, Some(512));
+    assert_eq!(errors[0].template_id, Some(512));
+    assert_eq!(errors[0].offset, 20);
+    assert_eq!(
+        errors[0].to_string(),
+        "Missing Template: 512 at offset 20, set 512, template 512"
+    );
+
+    // a set length running past the end of the message stops parsing
+    let mut truncated_bytes = bytes.to_vec();
+    truncated_bytes.extend_from_slice(&hex::decode("0102FFFF00").unwrap());
+    truncated_bytes[2..4].copy_from_slice(&(bytes.len() as u16 + 5).to_be_bytes());
+    let (msg, errors) =
+        parse_ipfix_message_lenient(&truncated_bytes, templates, formatter).unwrap();
+    assert_eq!(msg.sets.len(), 2);
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0].kind, IpfixError::BadSetLength(0xFFFF)));
+    assert_eq!(errors[0].set_id, Some(258));
+    assert_eq!(errors[0].offset, bytes.len() as u64);
+}
+
+#[test]
+fn test_message_framing() {
+    let bytes = include_bytes!("../resources/tests/lists_samp.bin");
+    let formatter = Rc::new(get_default_formatter());
+    let parse = |bytes: &[u8]| {
+        let templates = Rc::new(RefCell::new(HashMap::new()));
+        parse_ipfix_message(&bytes, templates, formatter.clone()).unwrap_err()
+    };
+
+    // bytes after the message are reported, but do not stop lenient parsing
+    let mut trailing_bytes = bytes.to_vec();
+    trailing_bytes.extend_from_slice(&[0, 0, 0]);
+    let error = parse(&trailing_bytes);
+    assert!(matches!(error.kind, IpfixError::TrailingBytes(3)));
+    assert_eq!(error.offset, bytes.len() as u64);
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let (msg, errors) =
+        parse_ipfix_message_lenient(&trailing_bytes, templates, formatter.clone()).unwrap();
+    assert_eq!(msg.sets.len(), 2);
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0].kind, IpfixError::TrailingBytes(3)));
+
+    // a message longer than the buffer is not parsed
+    let error = parse(&bytes[..100]);
+    assert!(matches!(
+        error.kind,
+        IpfixError::TruncatedMessage {
+            length: 112,
+            available: 100
+        }
+    ));
+    assert_eq!(error.offset, 2);
+
+    // nor is a length shorter than the header
+    let mut short_length = bytes.to_vec();
+    short_length[2..4].copy_from_slice(&15u16.to_be_bytes());
+    assert!(matches!(
+        parse(&short_length).kind,
+        IpfixError::BadMessageLength(15)
+    ));
+
+    // concatenated messages are split on their header lengths
+    let mut stream = bytes.to_vec();
+    stream.extend_from_slice(bytes);
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let messages: Vec<_> = parse_ipfix_messages(&stream, templates.clone(), formatter.clone())
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0], messages[1]);
+
+    // and a truncated message ends the iteration
+    let truncated = &stream[..200];
+    let mut results = parse_ipfix_messages(&truncated, templates, formatter.clone());
+    assert!(results.next().unwrap().is_ok());
+    assert!(matches!(
+        results.next().unwrap().unwrap_err().kind,
+        IpfixError::TruncatedMessage {
+            length: 112,
+            available: 88
+        }
+    ));
+    assert!(results.next().is_none());
+
+    // reading from a reader frames messages the same way, leaving the
+    // reader at the next message
+    let read = |reader: &mut Cursor<&[u8]>| {
+        let templates: TemplateStore = Rc::new(RefCell::new(HashMap::new()));
+        let args = (templates, formatter.clone(), ParseOptions::default());
+        Message::read_args(reader, args).map_err(Error::from)
+    };
+    let mut reader = Cursor::new(&stream[..]);
+    assert_eq!(read(&mut reader).unwrap(), messages[0]);
+    assert_eq!(reader.position(), bytes.len() as u64);
+    assert_eq!(read(&mut reader).unwrap(), messages[1]);
+    assert!(read(&mut reader).is_err());
+    let mut long_length = bytes[..100].to_vec();
+    long_length[2..4].copy_from_slice(&200u16.to_be_bytes());
+    let error = read(&mut Cursor::new(&long_length[..])).unwrap_err();
+    assert!(matches!(
+        error.kind,
+        IpfixError::TruncatedMessage {
+            length: 200,
+            available: 100
+        }
+    ));
+    let error = read(&mut Cursor::new(&short_length[..])).unwrap_err();
+    assert!(matches!(error.kind, IpfixError::BadMessageLength(15)));
+}
+
+#[test]
+fn test_parse_limits() {
+    let formatter = Rc::new(get_default_formatter());
+    let parse = |bytes: &[u8], limits: ParseLimits| {
+        let templates = Rc::new(RefCell::new(HashMap::new()));
+        let options = ParseOptions {
+            limits,
+            ..Default::default()
+        };
+        parse_ipfix_message_with_options(&bytes, templates, formatter.clone(), options)
+    };
+    let limit = |error: Error| match error.kind {
+        IpfixError::LimitExceeded { limit, max } => (limit, max),
+        kind => panic!("{kind}"),
+    };
+
+    // templates 256, 257 and 258, with a data set for 258 nesting 256 and 257
+    let lists = include_bytes!("../resources/tests/lists_samp.bin");
+    assert!(parse(lists, ParseLimits::default()).is_ok());
+    let limits = |f: fn(&mut ParseLimits)| {
+        let mut limits = ParseLimits::default();
+        f(&mut limits);
+        limits
+    };
+    let error = parse(lists, limits(|l| l.max_sets = 1)).unwrap_err();
+    assert_eq!(limit(error), (Limit::Sets, 1));
+    let error = parse(lists, limits(|l| l.max_records = 2)).unwrap_err();
+    assert_eq!(limit(error), (Limit::Records, 2));
+    let error = parse(lists, limits(|l| l.max_templates = 2)).unwrap_err();
+    assert_eq!(error.set_id, Some(2));
+    assert_eq!(limit(error), (Limit::Templates, 2));
+    let error = parse(lists, limits(|l| l.max_fields = 1)).unwrap_err();
+    assert_eq!(limit(error), (Limit::Fields, 1));
+    let error = parse(lists, limits(|l| l.max_bytes = 100)).unwrap_err();
+    assert_eq!(limit(error), (Limit::Bytes, 100));
+    assert!(parse(lists, limits(|l| l.max_depth = 1)).is_ok());
+    let error = parse(lists, limits(|l| l.max_depth = 0)).unwrap_err();
+    assert_eq!(limit(error), (Limit::Depth, 0));
+
+    // lenient parsing keeps the sets before the limit
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let options = ParseOptions {
+        limits: limits(|l| l.max_sets = 1),
+        ..Default::default()
+    };
+    let (msg, errors) =
+        parse_ipfix_message_lenient_with_options(lists, templates, formatter.clone(), options)
+            .unwrap();
+    assert_eq!(msg.sets.len(), 1);
+    assert_eq!(limit(errors.into_iter().next().unwrap()), (Limit::Sets, 1));
+
+    // 256: paddingOctets with length 0, so its records take no bytes
+    // 257: basicList of paddingOctets with length 0
+    let templates = "00020014\
+        0100000100D20000\
+        010100010123FFFF";
+    let empty_records = message(&format!("{templates}0100000500"));
+    let error = parse(&empty_records, ParseLimits::default()).unwrap_err();
+    assert_eq!(error.set_id, Some(256));
+    assert_eq!(limit(error), (Limit::Records, 1 << 16));
+
+    let empty_elements = message(&format!("{templates}0101000B060300D2000000"));
+    let error = parse(&empty_elements, ParseLimits::default()).unwrap_err();
+    assert!(matches!(error.kind, IpfixError::EmptyListElement));
+    assert_eq!(error.set_id, Some(257));
+    assert_eq!(error.field_index, Some(0));
+}
+
+#[test]
+fn test_nested_list_depth() {
+    // 256: a subTemplateList of records of 256, nested 10000 deep down to
+    // an empty list
+    let depth = 10000;
+    let mut lengths = vec![3usize];
+    for _ in 1..depth {
+        let inner = *lengths.last().unwrap();
+        let prefix = if inner < 255 { 1 } else { 3 };
+        lengths.push(3 + prefix + inner);
+    }
+    let mut record = Vec::new();
+    for &length in lengths.iter().rev() {
+        if length < 255 {
+            record.push(length as u8);
+        } else {
+            record.push(255);
+            record.extend((length as u16).to_be_bytes());
+        }
+        record.extend([0xFF, 0x01, 0x00]);
+    }
+    let data_set = format!("0100{:04X}{}", record.len() + 4, hex::encode_upper(&record));
+    let bytes = message(&format!("0002000C010000010124FFFF{data_set}"));
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+    let error = parse_ipfix_message(&bytes, templates.clone(), formatter.clone()).unwrap_err();
+    assert!(matches!(
+        error.kind,
+        IpfixError::LimitExceeded {
+            limit: Limit::Depth,
+            max: 16
+        }
+    ));
+    assert_eq!(error.set_id, Some(256));
+
+    // 257: a basicList of ingressInterface, with 1 byte of its 4 in the
+    // list, and the rest in the padding after it
+    let bytes = message("0002000C010100010123FFFF0101000E06FF000A000401000000");
+    let error = parse_ipfix_message(&bytes, templates, formatter).unwrap_err();
+    assert!(matches!(error.kind, IpfixError::TruncatedRecord));
+    assert_eq!(error.set_id, Some(257));
+}
+
+#[test]
+fn test_set_padding() {
+    // 256: interfaceName and interfaceDescription (variable length), and
+    // ingressInterface, so records take at least 6 bytes
+    let template_set = "00020014\
+        01000003\
+        0052FFFF0053FFFF000A0004";
+    let record = "036574680000000001";
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+    let parse = |bytes: &[u8], strict_padding: bool| {
+        let options = ParseOptions {
+            strict_padding,
+            ..Default::default()
+        };
+        parse_ipfix_message_with_options(&bytes, templates.clone(), formatter.clone(), options)
+    };
+
+    let msg = parse(&message(template_set), true).unwrap();
+    assert_eq!(msg.sets[0].padding, Some(vec![]));
+    assert_eq!(templates.borrow()[&256].min_record_length(), 6);
+
+    // 4 bytes are fewer than the shortest record, so are padding
+    let padded = message(&format!("01000011{record}00000000"));
+    let msg = parse(&padded, true).unwrap();
+    assert_eq!(msg.iter_data_records().count(), 1);
+    assert_eq!(msg.sets[0].padding, Some(vec![0; 4]));
+    let msg_ref = MessageRef::new(&padded).unwrap();
+    let set = msg_ref.sets().next().unwrap().unwrap();
+    let template = templates.borrow()[&256].clone();
+    assert_eq!(set.data_records(&template).count(), 1);
+
+    // padding that is not zeros is only an error when asked for
+    let bad_padding = message(&format!("01000011{record}00000100"));
+    let error = parse(&bad_padding, true).unwrap_err();
+    assert!(matches!(error.kind, IpfixError::NonZeroPadding));
+    assert_eq!(error.set_id, Some(256));
+    assert_eq!(error.offset, 16 + 4 + 9);
+    let msg = parse(&bad_padding, false).unwrap();
+    assert_eq!(msg.iter_data_records().count(), 1);
+    assert_eq!(msg.sets[0].padding, Some(vec![0, 0, 1, 0]));
+
+    // template sets too, which parse by default
+    let padded_template_set = message("0002000E010200010052FFFF0001");
+    let msg =
+        parse_ipfix_message(&padded_template_set, templates.clone(), formatter.clone()).unwrap();
+    assert_eq!(msg.sets[0].padding, Some(vec![0, 1]));
+    let error = parse(&padded_template_set, true).unwrap_err();
+    assert!(matches!(error.kind, IpfixError::NonZeroPadding));
+
+    // 257: only interfaceName, so its records may be 1 byte, and no bytes
+    // are too few for one, so empty records are kept
+    parse(&message("0002000C010100010052FFFF"), true).unwrap();
+    let empty_records = message("0101000B04657468300000");
+    let msg = parse(&empty_records, true).unwrap();
+    let names: Vec<_> = msg
+        .iter_data_records()
+        .map(|record| record.get(&DataRecordKey::Str("interfaceName")).cloned())
+        .collect();
+    let name = |name: &str| Some(DataRecordValue::String(name.into()));
+    assert_eq!(names, [name("eth0"), name(""), name("")]);
+    assert_eq!(msg.sets[0].padding, Some(vec![]));
+    let msg_ref = MessageRef::new(&empty_records).unwrap();
+    let set = msg_ref.sets().next().unwrap().unwrap();
+    let template = templates.borrow()[&257].clone();
+    assert_eq!(set.data_records(&template).count(), 3);
+
+    // a record running past the end of its set is not padding
+    let truncated = message(&format!("0100000B{}", &record[..14]));
+    let error = parse(&truncated, false).unwrap_err();
+    assert!(matches!(error.kind, IpfixError::TruncatedRecord));
+    assert_eq!(error.offset, 16 + 4);
+    let msg_ref = MessageRef::new(&truncated).unwrap();
+    let set = msg_ref.sets().next().unwrap().unwrap();
+    let template = templates.borrow()[&256].clone();
+    let error = set.data_records(&template).next().unwrap().unwrap_err();
+    assert!(matches!(error.kind, IpfixError::TruncatedRecord));
+    assert_eq!(error.offset, 16 + 4);
+    assert_eq!(error.set_id, Some(256));
+
+    // bytes after the last set, too few for a set header
+    let trailing = message(&format!("{template_set}0000"));
+    let error = parse(&trailing, false).unwrap_err();
+    assert!(matches!(error.kind, IpfixError::BadSetLength(0)));
+    let msg_ref = MessageRef::new(&trailing).unwrap();
+    let error = msg_ref.sets().nth(1).unwrap().unwrap_err();
+    assert!(matches!(error.kind, IpfixError::BadSetLength(0)));
+    assert_eq!(error.offset, 16 + 20);
+}
+
+#[test]
+fn test_scoped_templates() {
+    // both define template 256: as sourceIPv4Address, or octetDeltaCount
+    let source_template = "0002000C010000010008000401000008C0A80001";
+    let octets_template = "0002000C01000001000100080100000C0000000000000400";
+
+    let templates = ScopedTemplateStore::default();
+    let formatter = Rc::new(get_default_formatter());
+    let parse = |session: &'static str, bytes: &[u8]| {
+        parse_ipfix_message_scoped(&bytes, &templates, &session, formatter.clone())
+    };
+
+    // the same template id, from two exporters, or two Observation Domains
+    // of one exporter
+    let source = parse("a", &message_in_domain(1, source_template)).unwrap();
+    let octets = parse("b", &message_in_domain(1, octets_template)).unwrap();
+    let domain_octets = parse("a", &message_in_domain(2, octets_template)).unwrap();
+    assert_eq!(
+        source.iter_data_records().next().unwrap(),
+        &data_record! { "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(192, 168, 0, 1)) }
+    );
+    assert_eq!(
+        octets.iter_data_records().next().unwrap(),
+        &data_record! { "octetDeltaCount": U64(1024) }
+    );
+    assert_eq!(octets.sets, domain_octets.sets);
+
+    let key = |template: Template| template.field_specifiers()[0].name.clone();
+    let template = templates.get_template(&"a", 1, 256).unwrap();
+    assert_eq!(key(template), DataRecordKey::Str("sourceIPv4Address"));
+    let template = templates.get_template(&"a", 2, 256).unwrap();
+    assert_eq!(key(template), DataRecordKey::Str("octetDeltaCount"));
+    assert!(templates.get_template(&"b", 2, 256).is_none());
+
+    // data sets are read with the templates of their own scope
+    let data = message_in_domain(1, "01000008C0A80002");
+    // too short for b's template, so padding
+    let msg = parse("b", &data).unwrap();
+    assert_eq!(msg.iter_data_records().count(), 0);
+    let msg = parse("a", &data).unwrap();
+    assert_eq!(msg.iter_data_records().count(), 1);
+    let error = parse("c", &data).unwrap_err();
+    assert!(matches!(error.kind, IpfixError::MissingTemplate(256)));
+    assert_eq!(error.template_id, Some(256));
+    // a scope is only kept once a message in it parses
+    assert!(templates.get(&"c", 1).is_none());
+    // and the templates of a message that fails are not kept in an
+    // existing scope: here 256 is withdrawn and 257 defined, before a data
+    // set for the unknown template 300
+    let failing = message_in_domain(
+        1,
+        "00020008010000000002000C010100010008000401010008C0A80002012C0008C0A80002",
+    );
+    let error = parse("a", &failing).unwrap_err();
+    assert!(matches!(error.kind, IpfixError::MissingTemplate(300)));
+    assert!(templates.get_template(&"a", 1, 256).is_some());
+    assert!(templates.get_template(&"a", 1, 257).is_none());
+    let withdrawn = message_in_domain(
+        1,
+        "00020008010000000002000C010100010008000401010008C0A80002",
+    );
+    parse("a", &withdrawn).unwrap();
+    assert!(templates.get_template(&"a", 1, 256).is_none());
+    assert!(templates.get_template(&"a", 1, 257).is_some());
+
+    // as are the messages of a stream
+    let mut stream = message_in_domain(3, source_template);
+    stream.extend(message_in_domain(4, octets_template));
+    let messages: Vec<_> =
+        parse_ipfix_messages_scoped(&stream, &templates, &"d", formatter.clone())
+            .collect::<Result<_, _>>()
+            .unwrap();
+    assert_eq!(messages[1].sets, octets.sets);
+    assert!(templates.get_template(&"d", 3, 256).is_some());
+    assert!(templates.get_template(&"d", 4, 256).is_some());
+
+    templates.remove_session(&"a");
+    assert!(templates.get(&"a", 1).is_none());
+    assert!(templates.get(&"a", 2).is_none());
+    assert_eq!(templates.scopes().len(), 3);
+
+    // a session has a limited number of scopes, and templates across them
+    let limit = |error: Error| match error.kind {
+        IpfixError::LimitExceeded { limit, max } => (limit, max),
+        kind => panic!("{kind}"),
+    };
+    let templates = ScopedTemplateStore::default();
+    let parse = |session: &'static str, bytes: &[u8], limits: ParseLimits| {
+        let options = ParseOptions {
+            limits,
+            ..Default::default()
+        };
+        parse_ipfix_message_scoped_with_options(
+            &bytes,
+            &templates,
+            &session,
+            formatter.clone(),
+            options,
+        )
+    };
+    let limits = ParseLimits {
+        max_domains: 2,
+        max_templates: 2,
+        ..Default::default()
+    };
+    parse("a", &message_in_domain(1, source_template), limits).unwrap();
+    parse("a", &message_in_domain(2, source_template), limits).unwrap();
+    let error = parse("a", &message_in_domain(3, source_template), limits).unwrap_err();
+    assert_eq!(error.offset, 12);
+    assert_eq!(limit(error), (Limit::Domains, 2));
+    assert!(templates.get(&"a", 3).is_none());
+    parse("a", &message_in_domain(1, octets_template), limits).unwrap();
+    parse("b", &message_in_domain(3, source_template), limits).unwrap();
+
+    let limits = ParseLimits {
+        max_templates: 2,
+        ..Default::default()
+    };
+    let template_258 = "0002000C010200010008000401020008C0A80001";
+    let error = parse("a", &message_in_domain(1, template_258), limits).unwrap_err();
+    assert_eq!(limit(error), (Limit::Templates, 2));
+    let error = parse("a", &message_in_domain(4, template_258), limits).unwrap_err();
+    assert_eq!(limit(error), (Limit::Templates, 2));
+    assert!(templates.get(&"a", 4).is_none());
+}
+
+#[test]
+fn test_pending_data_sets() {
+    // templates 256, 257 and 258, with a data set for 258 nesting 256 and 257
+    let bytes = include_bytes!("../resources/tests/lists_samp.bin");
+    let mut data_bytes = hex::decode("000A0048000000000000000000000000").unwrap();
+    data_bytes.extend_from_slice(&bytes[56..]);
+
+    let store = Rc::new(BufferedTemplateStore::new(
+        RefCell::new(HashMap::new()),
+        PendingLimits {
+            max_bytes: 52,
+            ..Default::default()
+        },
+    ));
+    let formatter = Rc::new(get_default_formatter());
+
+    // without a template, the data set is held by the store
+    let msg = parse_ipfix_message(&data_bytes, store.clone(), formatter.clone()).unwrap();
+    assert!(matches!(
+        msg.sets[0].records,
+        Records::Pending { set_id: 258, .. }
+    ));
+    assert_eq!(store.pending_sets(), 1);
+    assert_eq!(store.pending_bytes(), 52);
+
+    let mut writer = Cursor::new(Vec::new());
+    msg.write_args(&mut writer, (store.clone(), formatter.clone(), 1))
+        .unwrap();
+    assert_eq!(writer.into_inner(), data_bytes);
+
+    // the oldest set is dropped to stay within the size limit
+    parse_ipfix_message(&data_bytes, store.clone(), formatter.clone()).unwrap();
+    assert_eq!(store.pending_sets(), 1);
+
+    // and decoded once the template arrives
+    let msg = parse_ipfix_message(bytes, store.clone(), formatter).unwrap();
+    assert_eq!(store.pending_sets(), 0);
+    assert_eq!(store.pending_bytes(), 0);
+    let released = store.take_released();
+    assert_eq!(released.len(), 1);
+    assert_eq!(released[0].as_ref().unwrap(), &msg.sets[1]);
+    assert!(store.take_released().is_empty());
+}
+
 #[test]
 fn concurrency() {
     // A state to be shared between parsing threads
@@ -220,3 +1474,233 @@ fn concurrency() {
     // Assert state mutated from threads
     assert!(templates.read().unwrap().len() == 3);
 }
+
+#[test]
+fn test_write_validation() {
+    // 256: sourceIPv4Address, octetDeltaCount with length 2
+    let template_bytes =
+        hex::decode("000A002000000000000000000000000000020010010000020008000400010002").unwrap();
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+    parse_ipfix_message(&template_bytes, templates.clone(), formatter.clone()).unwrap();
+
+    let write = |record: DataRecord, validation: Validation| {
+        let msg = Message {
+            export_time: 0,
+            sequence_number: 0,
+            observation_domain_id: 0,
+            sets: vec![Set {
+                records: Records::Data {
+                    set_id: 256,
+                    data: vec![record],
+                },
+                padding: None,
+            }],
+        };
+        let options = WriteOptions {
+            validation,
+            ..Default::default()
+        };
+        msg.to_bytes_with_options(templates.clone(), formatter.clone(), options)
+    };
+
+    let bytes = write(
+        data_record! {
+            "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)),
+            "octetDeltaCount": U16(1),
+        },
+        Validation::Strict,
+    )
+    .unwrap();
+    assert_eq!(hex::encode_upper(&bytes[16..]), "0100000A0A0000010001");
+
+    // integers of any width are written at the field's length
+    for octets in [
+        DataRecordValue::U8(1),
+        DataRecordValue::U64(1),
+        DataRecordValue::I32(1),
+    ] {
+        let mut record = data_record! {
+            "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)),
+        };
+        record
+            .values
+            .push((DataRecordKey::Str("octetDeltaCount"), octets.into()));
+        assert_eq!(write(record, Validation::Strict).unwrap(), bytes);
+    }
+    let err = write(
+        data_record! {
+            "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)),
+            "octetDeltaCount": U64(0x1_0000),
+        },
+        Validation::Permissive,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        IpfixError::ValueOutOfRange {
+            value: 0x1_0000,
+            length: 2
+        }
+    ));
+    assert_eq!(
+        (err.set_id, err.field_index, err.offset),
+        (Some(256), Some(1), 20)
+    );
+    let err = write(
+        data_record! {
+            "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)),
+            "octetDeltaCount": I8(-1),
+        },
+        Validation::Strict,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        IpfixError::ValueOutOfRange {
+            value: -1,
+            length: 2
+        }
+    ));
+
+    let err = write(
+        data_record! {
+            "sourceIPv4Address": Bytes(vec![10, 0, 0]),
+            "octetDeltaCount": U16(1),
+        },
+        Validation::Permissive,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        IpfixError::FieldLengthMismatch {
+            length: 4,
+            actual: 3
+        }
+    ));
+
+    // type mismatches and unused values are only errors in strict mode
+    let mismatched = data_record! {
+        "sourceIPv4Address": U32(0x0A00_0001),
+        "octetDeltaCount": U16(1),
+    };
+    let err = write(mismatched.clone(), Validation::Strict).unwrap_err();
+    assert!(matches!(
+        err.kind,
+        IpfixError::TypeMismatch {
+            expected: DataRecordType::Ipv4Addr,
+            actual: DataRecordType::UnsignedInt
+        }
+    ));
+    assert_eq!(err.field_index, Some(0));
+    assert_eq!(write(mismatched, Validation::Permissive).unwrap(), bytes);
+
+    let unused = data_record! {
+        "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)),
+        "octetDeltaCount": U16(1),
+        "packetDeltaCount": U64(1),
+    };
+    let err = write(unused.clone(), Validation::Strict).unwrap_err();
+    assert!(matches!(
+        err.kind,
+        IpfixError::UnusedData(DataRecordKey::Str("packetDeltaCount"))
+    ));
+    assert_eq!(write(unused, Validation::Permissive).unwrap(), bytes);
+}
+
+#[test]
+fn test_parse_ntp_timestamps() {
+    // 256: flowStartMicroseconds, flowEndNanoseconds
+    #[rustfmt::skip]
+    let bytes = hex::decode(concat!(
+        "000A0034000000000000000000000000",
+        "00020010", "01000002", "009A0008", "009D0008",
+        "01000014", "83AA7E8180000000", "83AA7E8200000001",
+    ))
+    .unwrap();
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+
+    let msg = parse_ipfix_message(&bytes, templates.clone(), formatter.clone()).unwrap();
+    let record = msg.iter_data_records().next().unwrap();
+    let Some(DataRecordValue::DateTimeMicroseconds(start)) =
+        record.get(&DataRecordKey::Str("flowStartMicroseconds"))
+    else {
+        panic!("flowStartMicroseconds is not a timestamp");
+    };
+    assert_eq!(start.unix_micros(), 1_500_000);
+    let Some(DataRecordValue::DateTimeNanoseconds(end)) =
+        record.get(&DataRecordKey::Str("flowEndNanoseconds"))
+    else {
+        panic!("flowEndNanoseconds is not a timestamp");
+    };
+    // the fraction is below a nanosecond, but kept when written
+    assert_eq!(end.unix_nanos(), 2_000_000_000);
+    assert_eq!(msg.to_bytes(templates, formatter, 1).unwrap(), bytes);
+}
+
+#[test]
+fn test_resolve_flow_times() {
+    let init = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let export_time = init + Duration::from_secs(60);
+    let mut resolver = TimestampResolver::new();
+
+    let options =
+        data_record! { "systemInitTimeMilliseconds": DateTimeMilliseconds(1_700_000_000_000) };
+    assert!(resolver.observe("exporter", &options));
+    assert_eq!(resolver.system_init_time(&"exporter"), Some(init));
+
+    let sys_up_time = data_record! {
+        "flowStartSysUpTime": U32(1_000),
+        "flowEndSysUpTime": U32(2_500),
+    };
+    assert_eq!(
+        resolver.resolve(&"exporter", export_time, &sys_up_time),
+        FlowTimes {
+            start: Some(ResolvedTime {
+                time: init + Duration::from_millis(1_000),
+                method: ResolutionMethod::SystemInitTime
+            }),
+            end: Some(ResolvedTime {
+                time: init + Duration::from_millis(2_500),
+                method: ResolutionMethod::SystemInitTime
+            }),
+        }
+    );
+    // other exporters have no system init time
+    assert_eq!(
+        resolver.resolve(&"other", export_time, &sys_up_time),
+        FlowTimes::default()
+    );
+
+    let mixed = data_record! {
+        "flowStartSeconds": DateTimeSeconds(1_700_000_010),
+        "flowEndDeltaMicroseconds": U32(1_500_000),
+    };
+    assert_eq!(
+        resolver.resolve(&"other", export_time, &mixed),
+        FlowTimes {
+            start: Some(ResolvedTime {
+                time: init + Duration::from_secs(10),
+                method: ResolutionMethod::Absolute
+            }),
+            end: Some(ResolvedTime {
+                time: export_time - Duration::from_micros(1_500_000),
+                method: ResolutionMethod::ExportTimeDelta
+            }),
+        }
+    );
+
+    // times that `SystemTime` cannot represent are not resolved
+    let latest = (0..64)
+        .rev()
+        .find_map(|bits| UNIX_EPOCH.checked_add(Duration::from_secs((1 << bits) - 1)))
+        .unwrap();
+    resolver.set_system_init_time("late", latest);
+    assert_eq!(
+        resolver.resolve(&"late", export_time, &sys_up_time),
+        FlowTimes::default()
+    );
+}
@@ -8,15 +8,18 @@ use test_case::test_case;
 
 use ipfixrw::{
     data_record,
+    error::Error,
     information_elements::{get_default_formatter, Formatter},
-    parse_ipfix_message,
+    parse_ipfix_message, parse_ipfix_message_with_options,
     parser::{
-        DataRecord, DataRecordKey, DataRecordType, DataRecordValue, FieldSpecifier, Message,
-        OptionsTemplateRecord, Records, Set, TemplateRecord,
+        DataRecord, DataRecordKey, DataRecordType, DataRecordValue, FieldSpecifier, IpfixError,
+        Message, OptionsTemplateRecord, ParseOptions, Records, Set, TemplateRecord,
     },
 };
 
 // receiver information templates
+// NOTE: the source document uses a scope field count of 0, which RFC 7011 section 3.4.2.2
+// forbids, so these are parsed with `allow_zero_scope_field_count`
 #[test_case(
         concat!(
             "00030024999200030000",
@@ -35,6 +38,7 @@ use ipfixrw::{
                     FieldSpecifier::new(Some(30351), 8, u16::MAX),
                 ],
             }]),
+            padding: Some(vec![0, 0]),
         } ; "receiverCallsign, receiverLocator, decodingSoftware")]
 #[test_case(
         concat!(
@@ -56,6 +60,7 @@ use ipfixrw::{
                     FieldSpecifier::new(Some(30351), 9, u16::MAX),
                 ],
             }]),
+            padding: Some(vec![0, 0]),
         } ; "receiverCallsign, receiverLocator, decodingSoftware, anntennaInformation")]
 // sender information templates
 #[test_case(
@@ -76,7 +81,8 @@ use ipfixrw::{
                     FieldSpecifier::new(Some(30351), 11, 1),
                     FieldSpecifier::new(None, 150, 4),
                 ],
-            }])
+            }]),
+            padding: Some(vec![]),
         } ; "senderCallsign, frequency, mode, informationSource (1 byte), flowStartSeconds")]
 #[test_case(
         concat!(
@@ -98,7 +104,8 @@ use ipfixrw::{
                     FieldSpecifier::new(Some(30351), 3, u16::MAX),
                     FieldSpecifier::new(None, 150, 4),
                 ],
-            }])
+            }]),
+            padding: Some(vec![]),
         } ; "senderCallsign, frequency, mode, informationSource (1 byte), senderLocator, flowStartSeconds")]
 #[test_case(
         concat!(
@@ -122,7 +129,8 @@ use ipfixrw::{
                     FieldSpecifier::new(Some(30351), 11, 1),
                     FieldSpecifier::new(None, 150, 4),
                 ],
-            }])
+            }]),
+            padding: Some(vec![]),
         } ; "senderCallsign, frequency, sNR (1 byte), iMD (1 byte), mode, informationSource (1 byte), flowStartSeconds")]
 #[test_case(
         concat!(
@@ -148,7 +156,8 @@ use ipfixrw::{
                     FieldSpecifier::new(Some(30351), 3, u16::MAX),
                     FieldSpecifier::new(None, 150, 4),
                 ],
-            }])
+            }]),
+            padding: Some(vec![]),
         } ; "senderCallsign, frequency, sNR (1 byte), iMD (1 byte), mode, informationSource (1 byte), senderLocator, flowStartSeconds")]
 
 fn test_template_example(bytes_str: &'static str, expected_set: Set) -> BinResult<()> {
@@ -157,9 +166,13 @@ fn test_template_example(bytes_str: &'static str, expected_set: Set) -> BinResul
     let templates = Rc::new(RefCell::new(HashMap::new()));
     let formatter = Rc::new(pskreporter_formatter());
 
+    let options = ParseOptions {
+        allow_zero_scope_field_count: true,
+        ..Default::default()
+    };
     let parsed = Set::read_args(
         &mut Cursor::new(template_bytes.clone()),
-        (templates.clone(), formatter.clone()),
+        (templates.clone(), formatter.clone(), options),
     )?;
     similar_asserts::assert_eq!(expected: expected_set, parsed: parsed);
 
@@ -170,6 +183,53 @@ fn test_template_example(bytes_str: &'static str, expected_set: Set) -> BinResul
     Ok(())
 }
 
+// scope field count must be non-zero and at most the field count
+#[test_case("00030024999200030000" ; "no scope fields")]
+#[test_case("00030024999200030004" ; "more scope fields than fields")]
+fn test_invalid_scope_field_count(header: &'static str) {
+    let template_bytes = hex::decode(format!(
+        "{header}8002FFFF0000768F8004FFFF0000768F8008FFFF0000768F0000"
+    ))
+    .unwrap();
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(pskreporter_formatter());
+
+    let error = Set::read_args(
+        &mut Cursor::new(template_bytes),
+        (templates.clone(), formatter, ParseOptions::default()),
+    )
+    .unwrap_err();
+    assert!(matches!(
+        Error::from(error).kind,
+        IpfixError::InvalidScopeFieldCount { .. }
+    ));
+    assert!(templates.borrow().is_empty());
+}
+
+// the receiver information templates, with receiverCallsign as the scope field
+#[test]
+fn test_scoped_template_example() -> BinResult<()> {
+    let template_bytes =
+        hex::decode("000300249992000300018002FFFF0000768F8004FFFF0000768F8008FFFF0000768F0000")
+            .unwrap();
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(pskreporter_formatter());
+
+    let parsed = Set::read_args(
+        &mut Cursor::new(template_bytes),
+        (templates.clone(), formatter, ParseOptions::default()),
+    )?;
+    let Records::OptionsTemplate(records) = parsed.records else {
+        panic!("{:?}", parsed.records);
+    };
+    assert_eq!(records[0].scope_field_count, 1);
+    assert_eq!(templates.borrow()[&0x9992].scope_field_count(), 1);
+
+    Ok(())
+}
+
 fn pskreporter_formatter() -> Formatter {
     let mut formatter = get_default_formatter();
 
@@ -226,6 +286,7 @@ fn test_full_examples() -> BinResult<()> {
                         FieldSpecifier::new(Some(30351), 8, u16::MAX),
                     ],
                 }]),
+                padding: Some(vec![0, 0]),
             },
             Set {
                 records: Records::Template(vec![TemplateRecord {
@@ -238,6 +299,7 @@ fn test_full_examples() -> BinResult<()> {
                         FieldSpecifier::new(None, 150, 4),
                     ],
                 }]),
+                padding: Some(vec![]),
             },
             Set {
                 records: Records::Data {
@@ -248,6 +310,7 @@ fn test_full_examples() -> BinResult<()> {
                         "decoderSoftware": String("Homebrew v5.6".into()),
                     }],
                 },
+                padding: Some(vec![0, 0]),
             },
             Set {
                 records: Records::Data {
@@ -269,6 +332,7 @@ fn test_full_examples() -> BinResult<()> {
                         },
                     ],
                 },
+                padding: Some(vec![0, 0]),
             },
         ],
     };
@@ -295,11 +359,36 @@ fn test_full_examples() -> BinResult<()> {
     let templates = Rc::new(RefCell::new(HashMap::new()));
     let formatter = Rc::new(pskreporter_formatter());
 
-    let full_message =
-        parse_ipfix_message(&full_packet_bytes, templates.clone(), formatter.clone())?;
+    // the options template's scope field count of 0 is only accepted when
+    // asked for
+    let error =
+        parse_ipfix_message(&full_packet_bytes, templates.clone(), formatter.clone()).unwrap_err();
+    assert!(matches!(
+        error.kind,
+        IpfixError::InvalidScopeFieldCount {
+            template_id: 0x9992,
+            scope_field_count: 0,
+            field_count: 3
+        }
+    ));
+
+    let options = ParseOptions {
+        allow_zero_scope_field_count: true,
+        ..Default::default()
+    };
+    let full_message = parse_ipfix_message_with_options(
+        &full_packet_bytes,
+        templates.clone(),
+        formatter.clone(),
+        options,
+    )?;
 
     similar_asserts::assert_eq!(expected: expected_full_message, actual: full_message);
 
+    let receiver = full_message.iter_data_records().next().unwrap();
+    assert!(receiver.scope_values().is_empty());
+    assert_eq!(receiver.option_values().len(), 3);
+
     let data_only_message =
         parse_ipfix_message(&data_only_packet_bytes, templates, formatter.clone())?;
 
@@ -310,3 +399,34 @@ fn test_full_examples() -> BinResult<()> {
 
     Ok(())
 }
+
+// the full example's receiver information, with receiverCallsign as the scope
+// field
+#[test]
+fn test_full_example_scope_field() -> BinResult<()> {
+    #[rustfmt::skip]
+        let packet_bytes = hex::decode(
+            concat!(
+                "000A0054479532720000000100000000",
+                "00030024", "999200030001", "8002FFFF0000768F", "8004FFFF0000768F", "8008FFFF0000768F", "0000",
+                "99920020", "044E314451", "06464E3432686E", "0D486F6D65627265772076352E36", "0000",
+            )).unwrap();
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(pskreporter_formatter());
+
+    let message = parse_ipfix_message(&packet_bytes, templates, formatter)?;
+
+    let receiver = message.iter_data_records().next().unwrap();
+    assert_eq!(receiver.scope_field_count, 1);
+    assert_eq!(
+        receiver.scope_values(),
+        [(
+            DataRecordKey::Str("receiverCallsign"),
+            DataRecordValue::String("N1DQ".into()).into()
+        )]
+    );
+    assert_eq!(receiver.option_values().len(), 2);
+
+    Ok(())
+}
@@ -4,7 +4,10 @@ use std::rc::Rc;
 
 use ahash::{HashMap, HashMapExt};
 use binrw::BinWrite;
-use ipfixrw::parse_ipfix_message;
+use ipfixrw::{
+    data_record, parse_ipfix_message,
+    parser::{DataRecord, DataRecordKey, DataRecordValue, Records},
+};
 use test_case::test_case;
 
 use ipfixrw::information_elements::get_default_formatter;
@@ -12,6 +15,7 @@ use ipfixrw::information_elements::get_default_formatter;
 #[test_case(&["parse_temp.bin", "parse_data.bin"], 1; "parse sample")]
 #[test_case(&["parse_temp_1.bin", "dns_samp.bin"], 4; "nprobe dns sample")]
 #[test_case(&["parse_temp_2.bin","http_samp.bin"], 4; "nprobe http sample")]
+#[test_case(&["lists_samp.bin"], 1; "structured data lists")]
 fn test_round_trip(filenames: &[&'static str], alignment: u8) -> binrw::BinResult<()> {
     let templates = Rc::new(RefCell::new(HashMap::new()));
     let formatter = Rc::new(get_default_formatter());
@@ -29,7 +33,74 @@ fn test_round_trip(filenames: &[&'static str], alignment: u8) -> binrw::BinResul
             (templates.clone(), formatter.clone(), alignment),
         )?;
         similar_asserts::assert_eq!(expected: file_bytes, actual: writer.into_inner().as_slice());
+
+        // writers without Seek
+        let mut writer = Vec::new();
+        msg.write_to(&mut writer, templates.clone(), formatter.clone(), alignment)?;
+        similar_asserts::assert_eq!(expected: file_bytes, actual: writer.as_slice());
+
+        // offsets past 64 KiB
+        let mut writer = Cursor::new(vec![0; 1 << 17]);
+        writer.set_position(1 << 17);
+        msg.write_args(
+            &mut writer,
+            (templates.clone(), formatter.clone(), alignment),
+        )?;
+        similar_asserts::assert_eq!(expected: file_bytes, actual: &writer.into_inner()[1 << 17..]);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_round_trip_encoding_details() -> binrw::BinResult<()> {
+    #[rustfmt::skip]
+    let bytes = hex::decode(concat!(
+        "000A0036000000000000000000000000",
+        // template 256: interfaceName (variable length), ingressInterface
+        "00020010", "01000002", "0052FFFF", "000A0004",
+        // a 3 byte length prefix for a short value, and 1 byte of padding
+        "01000016", "FF0003657468", "00000001", "026C6F", "00000002", "00",
+    ))
+    .unwrap();
+
+    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let formatter = Rc::new(get_default_formatter());
+
+    let mut msg = parse_ipfix_message(&bytes, templates.clone(), formatter.clone())?;
+    let records: Vec<_> = msg.iter_data_records().collect();
+    assert!(records[0].values[0].1.long_prefix);
+    assert!(!records[1].values[0].1.long_prefix);
+    assert_eq!(msg.sets[1].padding, Some(vec![0]));
+    // the prefix is not part of the value
+    assert_eq!(
+        *records[0],
+        data_record! {
+            "interfaceName": String("eth".into()),
+            "ingressInterface": U32(1),
+        }
+    );
+
+    let written = msg.to_bytes(templates.clone(), formatter.clone(), 4)?;
+    similar_asserts::assert_eq!(expected: bytes, actual: written);
+
+    // the prefix stays with its value when the values are reordered
+    if let Records::Data { data, .. } = &mut msg.sets[1].records {
+        data[0].values.reverse();
+    }
+    let written = msg.to_bytes(templates.clone(), formatter.clone(), 4)?;
+    similar_asserts::assert_eq!(expected: bytes, actual: written);
+
+    // without the recorded details, the shortest prefix and `alignment` are used
+    if let Records::Data { data, .. } = &mut msg.sets[1].records {
+        data[0].values[1].1.long_prefix = false;
     }
+    msg.sets[1].padding = None;
+    let written = msg.to_bytes(templates, formatter, 4)?;
+    assert_eq!(
+        hex::encode_upper(&written[32..]),
+        "010000140365746800000001026C6F0000000200"
+    );
 
     Ok(())
 }